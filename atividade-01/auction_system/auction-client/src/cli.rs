@@ -8,7 +8,7 @@ use std::{io::{self, stdout, Write}};
 use tokio::sync::mpsc::{Sender, Receiver};
 
 use crate::models::{CliCommand, Destructured};
-use shared::models::Bid;
+use shared::models::{Bid, Money};
 
 
 pub struct Cli {
@@ -17,6 +17,12 @@ pub struct Cli {
     scroll_offset: usize,
 }
 
+impl Default for Cli {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Cli {
     pub fn new() -> Self {
         Self {
@@ -30,6 +36,7 @@ impl Cli {
         mut self,
         make_bid_tx: Sender<Bid>,
         subscribe_tx: Sender<u32>,
+        query_tx: Sender<CliCommand>,
         mut cli_print_rx: Receiver<String>
     ) -> io::Result<()> {
         enable_raw_mode()?;
@@ -45,12 +52,10 @@ impl Cli {
             self.draw_ui()?;
             
             // Check for user input or scheduler messages
-            if event::poll(std::time::Duration::from_millis(100))? {
-                if let Event::Key(key_event) = event::read()? {
-                    if self.handle_key_event(key_event, &make_bid_tx, &subscribe_tx).await {
-                        break;
-                    }
-                }
+            if event::poll(std::time::Duration::from_millis(100))?
+                && let Event::Key(key_event) = event::read()?
+                && self.handle_key_event(key_event, &make_bid_tx, &subscribe_tx, &query_tx).await {
+                break;
             }
         }
         
@@ -71,7 +76,7 @@ impl Cli {
         for (i, message) in self.messages.iter().skip(self.scroll_offset).take(message_area_height as usize).enumerate(){
             execute!(
                 stdout(),
-                crossterm::cursor::MoveTo(0, (message_area_height - i as u16) as u16),
+                crossterm::cursor::MoveTo(0, message_area_height - i as u16),
                 crossterm::style::Print(message),
                 crossterm::terminal::Clear(crossterm::terminal::ClearType::UntilNewLine),
             )?;
@@ -95,6 +100,7 @@ impl Cli {
         key_event: KeyEvent,
         make_bid_tx: &Sender<Bid>,
         subscribe_tx: &Sender<u32>,
+        query_tx: &Sender<CliCommand>,
     ) -> bool {
         match key_event {
             KeyEvent {
@@ -122,6 +128,9 @@ impl Cli {
                                     self.send_make_bid(cmd, make_bid_tx).await;
                                 },
                                 CliCommand::Subscribe {..} => self.send_subscribe(cmd, subscribe_tx).await,
+                                CliCommand::List
+                                | CliCommand::Status {..}
+                                | CliCommand::History {..} => query_tx.send(cmd).await.unwrap(),
                             }
                         },
                         Err(e) => self.messages.push(format!("Error: {}\n", e)),
@@ -173,6 +182,19 @@ impl Cli {
             .collect();
         
         match parts.as_slice() {
+            ["list"] => Ok(CliCommand::List),
+            ["status", auction_id] => {
+                let auction_id = auction_id.parse::<u32>()
+                    .map_err(|_| "Invalid value: must be a positive integer".to_string())?;
+
+                Ok(CliCommand::Status { auction_id })
+            },
+            ["history", auction_id] => {
+                let auction_id = auction_id.parse::<u32>()
+                    .map_err(|_| "Invalid value: must be a positive integer".to_string())?;
+
+                Ok(CliCommand::History { auction_id })
+            },
             ["subscribe", auction_id] => {  
                 let auction_id = auction_id.parse::<u32>()
                     .map_err(|_| "Invalid value: must be a positive integer".to_string())?;
@@ -181,18 +203,26 @@ impl Cli {
                     auction_id,
                 })
             },
-            ["bid", auction_id, value] => {
-                let value = value.parse::<f64>()
-                    .map_err(|_| "Invalid value: must be a number".to_string())?;
+            ["bid", auction_id, value, quantity @ ..] if quantity.len() <= 1 => {
+                let value = value.parse::<Money>()
+                    .map_err(|e| format!("Invalid value: {e}"))?;
                 let auction_id = auction_id.parse::<u32>()
                     .map_err(|_| "Invalid value: must be a positive integer".to_string())?;
+                let quantity = match quantity.first() {
+                    Some(quantity) => quantity.parse::<u32>()
+                        .ok()
+                        .filter(|quantity| *quantity > 0)
+                        .ok_or_else(|| "Invalid quantity: must be a positive integer".to_string())?,
+                    None => 1,
+                };
         
                 Ok(CliCommand::MakeBid { 
-                    auction_id: auction_id, 
+                    auction_id, 
                     value, 
+                    quantity,
                 })
             },
-            _ => Err("Unknown command. Usage: subscribe <auction_id> | bid <auction_id> <value>".to_string()),
+            _ => Err("Unknown command. Usage: subscribe <auction_id> | bid <auction_id> <value>[currency] [quantity] | list | status <auction_id> | history <auction_id>".to_string()),
         }
     }
     
@@ -202,35 +232,35 @@ impl Cli {
 
     async fn send_make_bid(&mut self, cmd: CliCommand, make_bid_tx: &Sender<Bid>){
 
-        if let Some(destructured) = cmd.destructure() {
-            match destructured {
-                Destructured::MakeBid(auction_id, value) => {
-                    let bid = Bid{
-                        auction_id: auction_id, // assuming auction_id is a numeric string
-                        client_id: 0,
-                        value: value, // already f64, no need to parse
-                        signature: "aaa".to_string(),
-                        public_key: "aaa".to_string(),
-                        valid: false
+        if let Some(Destructured::MakeBid(auction_id, value, quantity)) = cmd.destructure() {
+            let bid = Bid{
+                auction_id, // assuming auction_id is a numeric string
+                client_id: 0,
+                value,
+                signature: "aaa".to_string(),
+                public_key: "aaa".to_string(),
+                valid: false,
+                quantity,
+                nonce: 0,
+                timestamp: 0,
+                signature_scheme: Default::default()
 
-                    };
-                    make_bid_tx
-                    .send(bid)
-                    .await
-                    .unwrap();
-                }
-                _ => { /* Ignore other commands */}
-            }
+            };
+            make_bid_tx
+            .send(bid)
+            .await
+            .unwrap();
         }
         
 
     }
 
     async fn send_subscribe(&mut self, cmd: CliCommand, subscribe_tx: &Sender<u32>){
-        let auction_id = cmd.get_auction_id();
-        subscribe_tx
-            .send(auction_id)
-            .await
-            .unwrap();
+        if let Some(auction_id) = cmd.get_auction_id() {
+            subscribe_tx
+                .send(auction_id)
+                .await
+                .unwrap();
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,145 @@
+use std::{
+    error::Error,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use shared::broker::Broker;
+use shared::config::BrokerConfig;
+use shared::models::{KeyOperation, KeyRequest, Query, QueryReply};
+use shared::rpc::RpcClient;
+use shared::signing::{self, KeyAlgorithm, KeyError, SignatureScheme, SigningKey};
+use shared::topology::{client_reply_queue, declare_exclusive_queue, REGISTRO_CHAVES};
+
+/// Passphrase of encrypted key files. Asked for on the terminal when unset.
+const PASSPHRASE_VAR: &str = "AUCTION_CLIENT_PASSPHRASE";
+
+/// `keygen <private_key_path> [--type rsa|ed25519|ecdsa] [--encrypt]`
+///
+/// Writes a new PKCS#8 private key readable only by its owner and prints the public
+/// half. Refuses to overwrite an existing file.
+pub fn keygen(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let Some((path, options)) = args.split_first() else {
+        return Err("Usage: keygen <private_key_path> [--type rsa|ed25519|ecdsa] [--encrypt]".into());
+    };
+
+    let mut algorithm = KeyAlgorithm::Rsa;
+    let mut encrypt = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--type" => algorithm = options.next().ok_or("--type needs a value")?.parse()?,
+            "--encrypt" => encrypt = true,
+            _ => return Err(format!("Unexpected argument: {option}").into()),
+        }
+    }
+
+    let passphrase = if encrypt { Some(read_passphrase(true)?) } else { None };
+    let key = SigningKey::generate(algorithm)?;
+    write_private_key(Path::new(path), key.to_pem(passphrase.as_deref())?.as_bytes())?;
+
+    println!("Wrote {algorithm:?} private key to {path}");
+    println!("{}", key.verifying_key().to_pem()?);
+    Ok(())
+}
+
+/// `enroll <client_id> <private_key_path> [--signature pss|pkcs1v15|ed25519|ecdsa]`
+///
+/// Registers the public half of the key with bid-srv, for a client id that has no key yet.
+pub async fn enroll(broker_config: BrokerConfig, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let [client_id, path, options @ ..] = args else {
+        return Err("Usage: enroll <client_id> <private_key_path> [--signature pss|pkcs1v15|ed25519|ecdsa]".into());
+    };
+    let client_id: u32 = client_id.parse()?;
+    let key = load_private_key(path)?;
+    let scheme = signature_scheme(&key, path, options)?;
+
+    let broker = Broker::new(broker_config);
+    let reply_queue = client_reply_queue(client_id);
+    broker.on_connect({
+        let reply_queue = reply_queue.clone();
+        move |channel| {
+            let reply_queue = reply_queue.clone();
+            async move {
+                declare_exclusive_queue(&channel, &reply_queue).await?;
+                Ok(())
+            }
+        }
+    });
+    broker.start().await;
+    let rpc = RpcClient::new(&broker, &reply_queue);
+
+    let public_key = key.verifying_key().to_pem()?;
+    let mut request = KeyRequest::new(client_id, KeyOperation::Register { public_key });
+    request.proof = Some(signing::sign_key_request(&request, scheme, &key)?);
+
+    match rpc.call(REGISTRO_CHAVES, Query::Key(request)).await? {
+        QueryReply::Key(Ok(())) => {
+            println!("Enrolled client {client_id}, run: auction-client {client_id} {path}");
+            Ok(())
+        }
+        QueryReply::Key(Err(reason)) => Err(format!("bid-srv refused the key: {reason}").into()),
+        other => Err(format!("Unexpected reply from bid-srv: {other:?}").into()),
+    }
+}
+
+/// Reads the key at `path`, asking for the passphrase if it is encrypted.
+pub fn load_private_key(path: &str) -> Result<SigningKey, Box<dyn Error>> {
+    let pem = fs::read_to_string(path)?;
+    match SigningKey::from_pem(&pem) {
+        Err(KeyError::Encrypted) => {
+            let passphrase = read_passphrase(false)?;
+            Ok(SigningKey::from_pem_with_passphrase(&pem, Some(&passphrase))?)
+        }
+        result => Ok(result?),
+    }
+}
+
+/// The scheme of `--signature`, or the default one of the key.
+pub fn signature_scheme(key: &SigningKey, path: &str, options: &[String]) -> Result<SignatureScheme, Box<dyn Error>> {
+    let scheme = match options {
+        [] => key.default_scheme(),
+        [flag, scheme] if flag == "--signature" => scheme.parse()?,
+        _ => return Err(format!("Unexpected arguments: {}", options.join(" ")).into()),
+    };
+    if !key.verifying_key().supports(scheme) {
+        return Err(format!("{scheme:?} cannot be used with the key in {path}").into());
+    }
+    Ok(scheme)
+}
+
+fn read_passphrase(confirm: bool) -> Result<String, Box<dyn Error>> {
+    let passphrase = match std::env::var(PASSPHRASE_VAR) {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            let passphrase = rpassword::prompt_password("Passphrase: ")?;
+            if confirm && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+                return Err("Passphrases do not match".into());
+            }
+            passphrase
+        }
+    };
+    if passphrase.is_empty() {
+        return Err(format!("The passphrase cannot be empty, check {PASSPHRASE_VAR} if it is set").into());
+    }
+    Ok(passphrase)
+}
+
+fn write_private_key(path: &Path, pem: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(pem)?;
+    file.sync_all()
+}
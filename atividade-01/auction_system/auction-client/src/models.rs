@@ -1,11 +1,16 @@
-use rsa::{ RsaPrivateKey};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use shared::models::Money;
+use shared::signing::{SignatureScheme, SigningKey};
 
 
 #[derive(Clone)]
 pub struct Client{
     pub id: u32,
-    pub subscribed_auctions: Vec<u32>,
-    pub private_key: RsaPrivateKey,
+    pub subscribed_auctions: Arc<Mutex<Vec<u32>>>,
+    pub private_key: SigningKey,
+    pub signature_scheme: SignatureScheme,
     pub public_key: String,
     pub notification_queue_name: String,
 }
@@ -17,29 +22,41 @@ pub enum CliCommand{
     },
     MakeBid{
         auction_id: u32,
-        value: f64
+        value: Money,
+        quantity: u32
+    },
+    List,
+    Status{
+        auction_id: u32
+    },
+    History{
+        auction_id: u32
     },
 }
 
 impl CliCommand{
-    pub fn get_auction_id(&self) -> u32{
+    pub fn get_auction_id(&self) -> Option<u32>{
         match self{
-            Self::Subscribe { auction_id } => *auction_id,
-            Self::MakeBid { auction_id, .. } => *auction_id
+            Self::Subscribe { auction_id } => Some(*auction_id),
+            Self::MakeBid { auction_id, .. } => Some(*auction_id),
+            Self::Status { auction_id } => Some(*auction_id),
+            Self::History { auction_id } => Some(*auction_id),
+            Self::List => None
         }
     }
 }
 
 pub enum Destructured {
-    MakeBid(u32, f64),
+    MakeBid(u32, Money, u32),
     Subscribe(u32),
 }
 
 impl CliCommand {
     pub fn destructure(self) -> Option<Destructured> {
         match self {
-            Self::MakeBid { auction_id, value } => Some(Destructured::MakeBid(auction_id, value)),
+            Self::MakeBid { auction_id, value, quantity } => Some(Destructured::MakeBid(auction_id, value, quantity)),
             Self::Subscribe { auction_id } => Some(Destructured::Subscribe(auction_id)),
+            Self::List | Self::Status { .. } | Self::History { .. } => None,
         }
     }
 }
@@ -1,33 +1,40 @@
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use lapin::options::{QueueBindOptions};
-use rsa::RsaPublicKey;
 use tokio::sync::{mpsc::{Receiver, Sender}};
-use rsa::pkcs8::DecodePublicKey;
 use chrono::{TimeZone};
 
 
-use lapin::{
-    options::{
-        BasicConsumeOptions, 
-        BasicPublishOptions, 
-    }, 
-    types::FieldTable, Channel, Connection
-};
-use futures_lite::stream::StreamExt;
-use rsa::{
-    Pkcs1v15Sign
-};
-use sha2::{Digest, Sha256};
-
-use base64::{engine::general_purpose, Engine as _};
-use serde_json;
+use lapin::types::FieldTable;
 use shared::models::{
     Bid, 
+    BidRejection,
+    Envelope,
+    MessageType,
     Notification,
     NotificationType,
     Auction,
+    AuctionKind,
+    AuctionSummary,
+    BidSummary,
+    Query,
+    QueryReply,
 };
-use crate::models::Client;
+use shared::rpc::{RpcClient, RpcError};
+use shared::signing;
+use shared::broker::{Broker, Publisher};
+use shared::topology::{
+    auction_routing_key,
+    auction_srv_instance,
+    auction_srv_queue,
+    client_rejection_queue,
+    client_started_queue,
+    CONSULTA_LANCES,
+    CONSULTA_LEILOES,
+    LANCE_REALIZADO,
+    NOTIFICACOES
+};
+use crate::models::{CliCommand, Client};
 use crate::cli::Cli;
 
 
@@ -38,29 +45,33 @@ use crate::cli::Cli;
 pub async fn task_cli(
     make_bid_tx: Sender<Bid>,
     subscribe_tx: Sender<u32>,
+    query_tx: Sender<CliCommand>,
     cli_print_rx: Receiver<String>,
     cli: Cli
 ){
-    cli.run(make_bid_tx, subscribe_tx, cli_print_rx).await.unwrap();
+    cli.run(make_bid_tx, subscribe_tx, query_tx, cli_print_rx).await.unwrap();
 
     eprintln!("Exiting CLI TASK");
 }
 
 pub async fn task_subscribe(
-    conn: Arc<Connection>,
-    mut client: Client,
+    broker: Arc<Broker>,
+    client: Client,
     cli_print_tx: Sender<String>,
     mut subscribe_rx: Receiver<u32>,
 ) {
-    let channel = conn.create_channel().await.unwrap();
+    let mut channel = broker.create_channel().await;
 
     while let Some(auction_id) = subscribe_rx.recv().await {
-        if let Some(_) = client.subscribed_auctions.iter().find(|&&a| a == auction_id){
+        let mut subscribed_auctions = client.subscribed_auctions.lock().await;
+        if subscribed_auctions.contains(&auction_id){
             continue;
         }
         
-        client.subscribed_auctions.push(auction_id);
-        let routing_key = format!("leilao_{}", auction_id);
+        // recorded before binding, so a reconnection in between still rebinds it
+        subscribed_auctions.push(auction_id);
+        drop(subscribed_auctions);
+        let routing_key = auction_routing_key(auction_id);
         
         let result = cli_print_tx
             .send(format!("[AUCTION] Subscribed to auction {}\n", auction_id))
@@ -70,145 +81,137 @@ pub async fn task_subscribe(
             eprintln!("Error!: {e}");
         }
         
-        channel
+        while let Err(e) = channel
             .queue_bind(
                 client.notification_queue_name.as_str(),
-                "notificacoes",
+                NOTIFICACOES,
                 routing_key.as_str(),
                 QueueBindOptions::default(),
                 FieldTable::default(),
             )
             .await
-            .unwrap();
+        {
+            eprintln!("Failed to bind to auction {auction_id} ({e}), retrying");
+            channel = broker.create_channel().await;
+        }
     }
 
 }
 
 pub async fn task_make_bid(
-    conn: Arc<Connection>,
+    broker: Arc<Broker>,
     client: Arc<Client>,
+    cli_print_tx: Sender<String>,
     mut make_bid_rx: Receiver<Bid>,
 ) {
-    let channel = conn.create_channel().await.unwrap();
+    let publisher = broker.publisher();
 
     while let Some(mut bid) = make_bid_rx.recv().await {
 
         bid.client_id = client.id;
+        // a copy of this bid is refused by bid-srv once it has seen the nonce
+        bid.nonce = rand::random();
+        bid.timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
 
-        let content = format!("{}:{}:{}", bid.auction_id, bid.client_id, bid.value).into_bytes();
-    
-        let hashed = Sha256::digest(content);
-
-        let thing: String = hashed.iter().map(|b| format!("{:02x}", b)).collect();
-        println!("signing->{}:{}:{}\n{}", bid.auction_id, bid.client_id, bid.value, thing);
+        println!("signing->{} ({:?})", String::from_utf8_lossy(&bid.signed_payload()), client.signature_scheme);
 
         // sign
-        let signature = client.private_key.sign(
-            Pkcs1v15Sign::new_unprefixed(),
-            &hashed,
-        ).unwrap();
-
-
-        bid.signature = general_purpose::STANDARD.encode(signature);
+        signing::sign_bid(&mut bid, client.signature_scheme, &client.private_key).unwrap();
         bid.public_key = client.public_key.clone();
 
-        let public_key: RsaPublicKey = RsaPublicKey::from_public_key_pem(bid.public_key.as_str()).unwrap();
-        println!("Signature valid: {}", public_key.verify(Pkcs1v15Sign::new_unprefixed(), &hashed, &base64::engine::general_purpose::STANDARD.decode(&bid.signature).unwrap()).is_ok());
+        println!("Signature valid: {}", signing::verify_bid(&bid, &client.private_key.verifying_key()).is_ok());
 
-        publish_bid(&channel, &bid).await.unwrap();
+        let message_id = publish_bid(&publisher, &bid).unwrap();
+        if let Err(e) = cli_print_tx
+            .send(format!("[BID] Sent bid of {} for {} unit(s) on auction {} (bid {})\n", bid.value, bid.quantity, bid.auction_id, message_id))
+            .await
+        {
+            eprintln!("Failed to send bid message to CLI: {}", e);
+        }
     }
 }
 
 pub async fn task_receive_notification(
-    conn: Arc<Connection>,
+    broker: Arc<Broker>,
     client: Arc<Client>,
     cli_print_tx: Sender<String>,
 ) {
+    let mut deliveries = broker.subscribe(
+        client.notification_queue_name.as_str(),
+        &format!("client-notification-consumer-{}", client.id),
+    );
+
+    loop {
+        let (delivery, envelope) = deliveries
+            .next_envelope::<Notification>(MessageType::Notification)
+            .await;
+        cli_print_tx.send(notification_line(&envelope.payload)).await.unwrap();
 
-    let channel = conn.create_channel().await.unwrap();
-
-    let mut consumer = channel
-        .basic_consume(
-            client.notification_queue_name.as_str(),
-            &format!("client-notification-consumer-{}", client.id),
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await
-        .unwrap();
-
-    while let Some(delivery) = consumer.next().await {
-        let delivery = delivery.unwrap();
-        delivery.ack(Default::default()).await.unwrap();
-
-        // Deserialize the notification
-        let notification: Notification = serde_json::from_slice(&delivery.data).unwrap();
-
-
-        match notification.get_notification_type() {
-            NotificationType::NewBid => {
-                cli_print_tx
-                    .send(format!(
-                        "[NOTIFICATION] New bid: auction={} client={} value={}\n",
-                        notification.get_auction_id(),
-                        notification.get_client_id(),
-                        notification.get_bid_value()
-                    ))
-                    .await
-                    .unwrap();
-            }
-            NotificationType::AuctionWinner => {
-                cli_print_tx
-                    .send(format!(
-                        "[NOTIFICATION] Auction winner: auction={} client={} value={}\n",
-                        notification.get_auction_id(),
-                        notification.get_client_id(),
-                        notification.get_bid_value()
-                    ))
-                    .await
-                    .unwrap();
-            }
+        if let Err(e) = delivery.ack(Default::default()).await {
+            eprintln!("Failed to ack notification, it will be redelivered: {e}");
         }
     }
 
 }
 
+pub async fn task_receive_rejection(
+    broker: Arc<Broker>,
+    client: Arc<Client>,
+    cli_print_tx: Sender<String>,
+) {
+    let mut deliveries = broker.subscribe(
+        &client_rejection_queue(client.id),
+        &format!("client-rejection-consumer-{}", client.id),
+    );
+
+    loop {
+        let (delivery, envelope) = deliveries
+            .next_envelope::<BidRejection>(MessageType::BidRejected)
+            .await;
+        let line = rejection_line(&envelope.payload, envelope.correlation_id.as_deref());
+        if let Err(e) = cli_print_tx.send(line).await {
+            eprintln!("Failed to send rejection message to CLI: {}", e);
+        }
+
+        if let Err(e) = delivery.ack(Default::default()).await {
+            eprintln!("Failed to ack rejection, it will be redelivered: {e}");
+        }
+    }
+}
+
 pub async fn task_init_auction(
-    conn: Arc<Connection>,
-    started_queue_name: String,
+    broker: Arc<Broker>,
     client: Arc<Client>,
     cli_print_tx: Sender<String>,
 ){
-    let channel = conn.create_channel().await.unwrap();
-
-    let mut consumer = channel.basic_consume(
-            started_queue_name.as_str(), 
-            &format!("client-started-consumer-{}", client.id),
-            BasicConsumeOptions::default(), 
-            FieldTable::default()
-        )
-        .await
-        .unwrap();
-
-    while let Some(delivery) = consumer.next().await {
-        let delivery = delivery.unwrap();
-        delivery.ack(Default::default()).await.unwrap();
-
-        let auction: Auction = serde_json::from_slice(&delivery.data).unwrap();
+    let mut deliveries = broker.subscribe(
+        &client_started_queue(client.id),
+        &format!("client-started-consumer-{}", client.id),
+    );
+
+    loop {
+        let (delivery, envelope) = deliveries
+            .next_envelope::<Auction>(MessageType::AuctionStarted)
+            .await;
+        let auction = envelope.payload;
         
         if let Err(e) = cli_print_tx
             .send(format!(
                 "[AUCTION] New auction for {} started now ({}) with ID {}. Auction ends at {}.\n",
                 auction.item,
-                chrono::Local.timestamp_opt((auction.start_timestamp / 1000).try_into().unwrap(), 0).unwrap().format("%d/%m/%Y %H:%M:%S"),
+                format_timestamp(auction.start_timestamp),
                 auction.id,
-                chrono::Local.timestamp_opt((auction.end_timestamp / 1000).try_into().unwrap(), 0).unwrap().format("%d/%m/%Y %H:%M:%S")
+                format_timestamp(auction.end_timestamp)
             ))
             .await
         {
             eprintln!("Failed to send auction message to CLI: {}", e);
         }
 
+        if let Err(e) = delivery.ack(Default::default()).await {
+            eprintln!("Failed to ack auction start, it will be redelivered: {e}");
+        }
+
 
 
     }
@@ -217,33 +220,277 @@ pub async fn task_init_auction(
 
 }
 
+/// Runs the `list`, `status` and `history` commands against auction-srv and bid-srv.
+pub async fn task_query(
+    rpc: RpcClient,
+    auction_srv_instances: u32,
+    cli_print_tx: Sender<String>,
+    mut query_rx: Receiver<CliCommand>,
+) {
+    while let Some(cmd) = query_rx.recv().await {
+        let output = match cmd {
+            CliCommand::List => {
+                // each auction-srv instance only knows the auctions it owns
+                let queues: Vec<String> = (0..auction_srv_instances)
+                    .map(|instance| auction_srv_queue(CONSULTA_LEILOES, instance))
+                    .collect();
+                Ok(auction_list(rpc.call_all(&queues, Query::ListAuctions).await))
+            }
+            CliCommand::Status { auction_id } => auction_status(&rpc, auction_id, auction_srv_instances).await,
+            CliCommand::History { auction_id } => rpc
+                .call(CONSULTA_LANCES, Query::GetBidHistory { auction_id })
+                .await
+                .map(|reply| bid_history(auction_id, reply)),
+            _ => continue,
+        };
+
+        let lines = output.unwrap_or_else(|e| vec![format!("[QUERY] Failed: {e}\n")]);
+        for line in lines {
+            if let Err(e) = cli_print_tx.send(line).await {
+                eprintln!("Failed to send query result to CLI: {}", e);
+            }
+        }
+    }
+}
+
 /*==================================================== TASKS - END ====================================================*/
 
 
 /*====================================================== AUX ====================================================== */
 
+/// Merges the auctions of every auction-srv instance, saying which ones did not answer.
+fn auction_list(replies: Vec<Result<QueryReply, RpcError>>) -> Vec<String> {
+    let mut auctions = Vec::new();
+    let mut problems = Vec::new();
+    for reply in replies {
+        match reply {
+            Ok(QueryReply::Auctions(found)) => auctions.extend(found),
+            Ok(other) => problems.push(format!("[LIST] Unexpected reply: {other:?}\n")),
+            Err(e) => problems.push(format!("[LIST] Incomplete, {e}\n")),
+        }
+    }
+    auctions.sort_by_key(|summary| summary.auction.id);
+
+    let mut lines: Vec<String> = auctions
+        .iter()
+        .map(|summary| format!("[LIST] {}\n", format_auction(summary)))
+        .collect();
+    if lines.is_empty() && problems.is_empty() {
+        lines.push("[LIST] No auctions\n".to_string());
+    }
+    lines.extend(problems);
+    lines
+}
+
+fn notification_line(notification: &Notification) -> String {
+    match notification.get_notification_type() {
+        NotificationType::NewBid => format!(
+            "[NOTIFICATION] New bid: auction={} client={} value={} quantity={}\n",
+            notification.get_auction_id(),
+            notification.get_client_id(),
+            notification.get_bid_value(),
+            notification.get_quantity()
+        ),
+        NotificationType::AuctionWinner => format!(
+            "[NOTIFICATION] Auction winner: auction={} client={} value={} quantity={}\n",
+            notification.get_auction_id(),
+            notification.get_client_id(),
+            notification.get_bid_value(),
+            notification.get_quantity()
+        ),
+        NotificationType::ReserveNotMet => format!(
+            "[NOTIFICATION] Reserve not met, no winner: auction={} highest bid={}\n",
+            notification.get_auction_id(),
+            notification.get_bid_value()
+        ),
+        NotificationType::AuctionCancelled => format!(
+            "[NOTIFICATION] Auction cancelled: auction={}\n",
+            notification.get_auction_id()
+        ),
+        NotificationType::AuctionExtended => format!(
+            "[NOTIFICATION] Auction extended: auction={} end={}\n",
+            notification.get_auction_id(),
+            notification.get_end_timestamp().unwrap_or_default()
+        ),
+    }
+}
+
+/// `bid_message_id` is the id the bid was published under, which the rejection is correlated with.
+fn rejection_line(rejection: &BidRejection, bid_message_id: Option<&str>) -> String {
+    format!(
+        "[REJECTED] Bid of {} on auction {} was rejected: {} (bid {})\n",
+        rejection.value,
+        rejection.auction_id,
+        rejection.reason,
+        bid_message_id.unwrap_or("-")
+    )
+}
+
+fn bid_history(auction_id: u32, reply: QueryReply) -> Vec<String> {
+    match reply {
+        QueryReply::BidHistory(bids) if bids.is_empty() => vec![format!("[HISTORY] No bids on auction {auction_id}\n")],
+        QueryReply::BidHistory(bids) => bids
+            .iter()
+            .map(|bid| format!("[HISTORY] auction={auction_id} {}\n", format_bid(bid)))
+            .collect(),
+        other => vec![format!("[HISTORY] Unexpected reply: {other:?}\n")],
+    }
+}
+
+/// Combines the auction from the auction-srv instance that owns it with its highest bid from bid-srv.
+async fn auction_status(rpc: &RpcClient, auction_id: u32, auction_srv_instances: u32) -> Result<Vec<String>, RpcError> {
+    let owner = auction_srv_queue(CONSULTA_LEILOES, auction_srv_instance(auction_id, auction_srv_instances));
+    let auction = rpc.call(&owner, Query::GetAuction { auction_id }).await?;
+    let highest = rpc.call(CONSULTA_LANCES, Query::GetHighestBid { auction_id }).await?;
+
+    let line = match (auction, highest) {
+        (QueryReply::Auction(None), _) => format!("[STATUS] Auction {auction_id} not found\n"),
+        (QueryReply::Auction(Some(summary)), QueryReply::HighestBid(highest)) => format!(
+            "[STATUS] {} | highest bid: {}\n",
+            format_auction(&summary),
+            highest.as_ref().map(format_bid).unwrap_or_else(|| "none".to_string())
+        ),
+        other => format!("[STATUS] Unexpected reply: {other:?}\n"),
+    };
+
+    Ok(vec![line])
+}
+
+fn format_auction(summary: &AuctionSummary) -> String {
+    let auction = &summary.auction;
+    let mut line = format!(
+        "auction={} item={} status={:?} start={} end={}",
+        auction.id,
+        auction.item,
+        summary.status,
+        format_timestamp(auction.start_timestamp),
+        format_timestamp(auction.end_timestamp)
+    );
+    if let Some(starting_price) = auction.pricing.starting_price {
+        line.push_str(&format!(" starting_price={starting_price}"));
+    }
+    if let Some(min_increment) = auction.pricing.min_increment {
+        line.push_str(&format!(" min_increment={min_increment}"));
+    }
+    if let Some(reserve_price) = auction.pricing.reserve_price {
+        line.push_str(&format!(" reserve_price={reserve_price}"));
+    }
+    if let Some(soft_close_secs) = auction.soft_close_secs {
+        line.push_str(&format!(" soft_close={soft_close_secs}s"));
+    }
+    if auction.kind != AuctionKind::English {
+        line.push_str(&format!(" kind={:?}", auction.kind));
+    }
+    if auction.quantity > 1 {
+        line.push_str(&format!(" quantity={} pricing={:?}", auction.quantity, auction.unit_pricing));
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    if let Some(current_price) = auction.dutch_price(now) {
+        line.push_str(&format!(" current_price={current_price}"));
+    }
+    line
+}
+
+/// Falls back to the raw milliseconds for times chrono cannot represent.
+fn format_timestamp(timestamp: u128) -> String {
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|millis| chrono::Local.timestamp_millis_opt(millis).single())
+        .map(|time| time.format("%d/%m/%Y %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+fn format_bid(bid: &BidSummary) -> String {
+    format!("client={} value={} quantity={}", bid.client_id, bid.value, bid.quantity)
+}
+
 /*============================================= PUBLISH ============================================= */
 
 
-async fn publish_bid(
-    channel: &Channel, 
+/// Returns the message id, which rejections refer back to.
+fn publish_bid(
+    publisher: &Publisher, 
     bid: &Bid
-) -> Result<(), Box<dyn std::error::Error>> {
-    let payload = serde_json::to_vec(bid)?;
-    channel
-        .basic_publish(
-            "",
-            "lance_realizado",
-            BasicPublishOptions::default(),
-            &payload,
-            lapin::BasicProperties::default(),
-        )
-        .await?
-        .await?;
-    Ok(())
+) -> Result<String, Box<dyn std::error::Error>> {
+    let envelope = Envelope::new(MessageType::BidPlaced, bid);
+    publisher.publish_envelope(
+        "",
+        LANCE_REALIZADO,
+        &envelope,
+    )?;
+    Ok(envelope.message_id)
 }  
 
 /*============================================= PUBLISH - END ============================================= */
 
 
 /*============================================= BID VERIFICATION - END ============================================= */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::models::{AuctionStatus, RejectionReason};
+
+    fn summary(id: u32) -> AuctionSummary {
+        AuctionSummary { auction: Auction::new(id, format!("item {id}"), 0, 1_000), status: AuctionStatus::Open }
+    }
+
+    #[test]
+    fn list_merges_every_instance_and_names_the_silent_ones() {
+        let lines = auction_list(vec![
+            Ok(QueryReply::Auctions(vec![summary(3), summary(1)])),
+            Ok(QueryReply::Auctions(vec![summary(2)])),
+            Err(RpcError::Timeout("consulta_leiloes.2".to_string())),
+        ]);
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("[LIST] auction=1 "));
+        assert!(lines[1].starts_with("[LIST] auction=2 "));
+        assert!(lines[2].starts_with("[LIST] auction=3 "));
+        assert!(lines[3].starts_with("[LIST] Incomplete") && lines[3].contains("consulta_leiloes.2"));
+
+        assert_eq!(auction_list(vec![Ok(QueryReply::Auctions(Vec::new()))]), ["[LIST] No auctions\n"]);
+    }
+
+    #[test]
+    fn out_of_range_times_are_printed_as_millis() {
+        let far = Auction::new(1, "lamp".to_string(), 0, u128::MAX);
+        let line = format_auction(&AuctionSummary { auction: far, status: AuctionStatus::Scheduled });
+        assert!(line.contains(&format!("end={}", u128::MAX)));
+        assert_eq!(format_timestamp(i64::MAX as u128), i64::MAX.to_string());
+    }
+
+    #[test]
+    fn rejections_name_the_reason_and_the_bid_they_answer() {
+        let rejection = BidRejection {
+            auction_id: 3,
+            client_id: 1,
+            value: "9.50".parse().unwrap(),
+            reason: RejectionReason::AuctionClosed,
+        };
+        assert_eq!(
+            rejection_line(&rejection, Some("m-1")),
+            "[REJECTED] Bid of 9.50 BRL on auction 3 was rejected: auction is closed (bid m-1)\n"
+        );
+        assert!(rejection_line(&rejection, None).ends_with("(bid -)\n"));
+    }
+
+    #[test]
+    fn notifications_are_printed_by_type() {
+        let line = notification_line(&Notification::auction_cancelled(5));
+        assert_eq!(line, "[NOTIFICATION] Auction cancelled: auction=5\n");
+        let line = notification_line(&Notification::auction_extended(5, 1_000));
+        assert_eq!(line, "[NOTIFICATION] Auction extended: auction=5 end=1000\n");
+    }
+
+    #[test]
+    fn history_lists_every_bid_or_says_there_are_none() {
+        let bid = BidSummary { client_id: 2, value: "10".parse().unwrap(), quantity: 1 };
+        assert_eq!(
+            bid_history(7, QueryReply::BidHistory(vec![bid])),
+            ["[HISTORY] auction=7 client=2 value=10.00 BRL quantity=1\n"]
+        );
+        assert_eq!(bid_history(7, QueryReply::BidHistory(Vec::new())), ["[HISTORY] No bids on auction 7\n"]);
+        assert!(bid_history(7, QueryReply::Unsupported)[0].starts_with("[HISTORY] Unexpected reply"));
+    }
+}
@@ -3,46 +3,62 @@ use crossterm::{
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use std::{io::{self, stdout, Write}};
+use std::{collections::{BTreeMap, HashMap}, io::{self, stdout, Write}};
 
-use tokio::sync::mpsc::{Sender};
+use chrono::{Local, TimeZone};
+use tokio::sync::{mpsc::{Receiver, Sender}, watch};
+
+use shared::models::{AuctionKind, AuctionSummary, Money, UnitPricing};
+use crate::models::{AuctionOptions, CliCommand, ScheduleCommand};
+use crate::time_input::{parse_end, parse_schedule, tokenize};
 
-use shared::models::Auction;
-use crate::models::CliCommand;
 pub struct Cli {
     command_input: String,
     messages: Vec<String>,
     scroll_offset: usize,
-    auctions: Vec<Auction>
+    /// Latest state of every auction, published by the cron.
+    auctions_rx: watch::Receiver<BTreeMap<u32, AuctionSummary>>,
+    /// Highest bid seen on `notificacoes` for each auction.
+    high_bids_rx: watch::Receiver<HashMap<u32, Money>>,
+    show_auctions: bool
 }
 
 impl Cli {
-    pub fn new(auctions: Option<Vec<Auction>>) -> Self {
+    pub fn new(
+        auctions_rx: watch::Receiver<BTreeMap<u32, AuctionSummary>>,
+        high_bids_rx: watch::Receiver<HashMap<u32, Money>>
+    ) -> Self {
         Self {
             command_input: String::new(),
             messages: Vec::new(),
             scroll_offset: 0,
-            auctions: auctions.unwrap_or(Vec::new())
+            auctions_rx,
+            high_bids_rx,
+            show_auctions: false
         }
     }
     
     pub async fn run(
         mut self,
-        tx: Sender<Auction>,
+        tx: Sender<ScheduleCommand>,
+        mut cli_print_rx: Receiver<String>
     ) -> io::Result<()> {
         enable_raw_mode()?;
         let mut stdout = stdout();
         execute!(stdout, EnterAlternateScreen)?;
         loop {
+            // Outcome of the commands applied by the cron
+            while let Ok(message) = cli_print_rx.try_recv() {
+                self.messages.push(message);
+            }
+
             self.draw_ui()?;
             
             // Check for user input or scheduler messages
-            if event::poll(std::time::Duration::from_millis(100))? {
-                if let Event::Key(key_event) = event::read()? {
-                    if self.handle_key_event(key_event, &tx).await {
-                        break;
-                    }
-                }
+            if event::poll(std::time::Duration::from_millis(100))?
+                && let Event::Key(key_event) = event::read()?
+                && self.handle_key_event(key_event, &tx).await {
+                break;
             }
         }
         
@@ -55,17 +71,14 @@ impl Cli {
         // Clear screen and set cursor position
         execute!(stdout(), crossterm::terminal::Clear(crossterm::terminal::ClearType::All))?;
         
-        // Draw messages
         let (rows, _cols) = crossterm::terminal::size()?;
         let message_area_height = rows - 2;
-        
-        for (i, message) in self.messages.iter().skip(self.scroll_offset).take(message_area_height as usize).enumerate(){
-            execute!(
-                stdout(),
-                crossterm::cursor::MoveTo(0, (message_area_height - i as u16) as u16),
-                crossterm::style::Print(message),
-                crossterm::terminal::Clear(crossterm::terminal::ClearType::UntilNewLine),
-            )?;
+
+        if self.show_auctions {
+            self.draw_auctions(message_area_height)?;
+        }
+        else {
+            self.draw_messages(message_area_height)?;
         }
         
         // Draw input line
@@ -80,11 +93,58 @@ impl Cli {
         stdout().flush()?;
         Ok(())
     }
+
+    fn draw_messages(&self, message_area_height: u16) -> io::Result<()> {
+        for (i, message) in self.messages.iter().skip(self.scroll_offset).take(message_area_height as usize).enumerate(){
+            execute!(
+                stdout(),
+                crossterm::cursor::MoveTo(0, message_area_height - i as u16),
+                crossterm::style::Print(message),
+                crossterm::terminal::Clear(crossterm::terminal::ClearType::UntilNewLine),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Live table of the auctions, redrawn every frame from the cron's latest state.
+    fn draw_auctions(&self, message_area_height: u16) -> io::Result<()> {
+        let auctions = self.auctions_rx.borrow();
+        let high_bids = self.high_bids_rx.borrow();
+
+        let mut lines = vec![
+            format!("{:<4} {:<24} {:<20} {:<20} {:<10} {:>10}", "ID", "ITEM", "START", "END", "STATUS", "HIGH BID"),
+        ];
+        lines.extend(auctions.values().skip(self.scroll_offset).map(|summary| {
+            let auction = &summary.auction;
+            format!(
+                "{:<4} {:<24} {:<20} {:<20} {:<10} {:>10}",
+                auction.id,
+                auction.item,
+                format_timestamp(auction.start_timestamp),
+                format_timestamp(auction.end_timestamp),
+                format!("{:?}", summary.status),
+                high_bids.get(&auction.id).map(|value| value.to_string()).unwrap_or_else(|| "-".to_string())
+            )
+        }));
+        lines.push("(Esc to go back to the messages)".to_string());
+
+        for (i, line) in lines.iter().take(message_area_height as usize).enumerate(){
+            execute!(
+                stdout(),
+                crossterm::cursor::MoveTo(0, i as u16),
+                crossterm::style::Print(line),
+                crossterm::terminal::Clear(crossterm::terminal::ClearType::UntilNewLine),
+            )?;
+        }
+
+        Ok(())
+    }
     
     async fn handle_key_event(
         &mut self,
         key_event: KeyEvent,
-        new_auction_tx: &Sender<Auction>,
+        schedule_tx: &Sender<ScheduleCommand>,
     ) -> bool {
         match key_event {
             KeyEvent {
@@ -104,15 +164,28 @@ impl Cli {
                     self.messages.push(format!("> {}\n", command));
                     
                     // Process command
+                    self.show_auctions = false;
                     match self.parse_command(command){
+                        Ok(CliCommand::ListAuctions) => {
+                            self.show_auctions = true;
+                            self.scroll_offset = 0;
+                        },
                         Ok(cmd)=>{
-                            self.send_new_auction(cmd, new_auction_tx).await;
+                            self.send_schedule_command(cmd, schedule_tx).await;
                         },
                         Err(e) => self.messages.push(format!("Error: {}\n", e)),
                     }
                 }
             }
             
+            KeyEvent {
+                code: KeyCode::Esc,
+                ..
+            } => {
+                self.show_auctions = false;
+                self.scroll_offset = 0;
+            }
+            
             KeyEvent {
                 code: KeyCode::Backspace,
                 ..
@@ -151,48 +224,145 @@ impl Cli {
         &mut self,
         command_string: String,
     ) -> Result<CliCommand, String> {
-        let command_string_lc = command_string.to_ascii_lowercase();
-        let parts: Vec<&str> = command_string_lc
-            .split_whitespace()
-            .collect();
+        let tokens = tokenize(&command_string)?;
+        let (command, args) = tokens.split_first().ok_or("Empty command")?;
+        let now = Local::now();
         
-        match parts.as_slice() {
-            ["create", item, start_timestamp, end_timestamp] => {
-                // Parse time and duration
-                let start_timestamp: u64 = start_timestamp.parse()
-                    .map_err(|_| "Invalid Timestamp format".to_string())?;
-                
-                let end_timestamp: u64 = end_timestamp.parse()
-                    .map_err(|_| "Invalid duration".to_string())?;
-                
-                // Schedule the auction events
+        match (command.to_ascii_lowercase().as_str(), args) {
+            ("create", [item, rest @ ..]) => {
+                let options_at = rest.iter().position(|token| token.starts_with("--")).unwrap_or(rest.len());
+                let (schedule, options) = rest.split_at(options_at);
+                let (start_timestamp, end_timestamp) = parse_schedule(schedule, now)?;
+                let options = parse_create_options(options)?;
             
                 Ok(
                     CliCommand::CreateAuction { 
                         item: item.to_string(), 
                         start_timestamp, 
-                        end_timestamp
+                        end_timestamp,
+                        options
                     }
                 )
             },
-            ["list"] =>{
+            ("list", []) =>{
                 Ok(CliCommand::ListAuctions)
             }
-            _ => Err("Unknown command. Usage: create <time> <start_timestamp> <end_tim>".to_string()),
+            ("cancel", [auction_id]) => {
+                Ok(CliCommand::Cancel { auction_id: parse_auction_id(auction_id)? })
+            }
+            ("extend", [auction_id, end @ ..]) => {
+                Ok(CliCommand::Extend {
+                    auction_id: parse_auction_id(auction_id)?,
+                    end_timestamp: parse_end(end, now)?
+                })
+            }
+            ("reschedule", [auction_id, schedule @ ..]) => {
+                let (start_timestamp, end_timestamp) = parse_schedule(schedule, now)?;
+                Ok(CliCommand::Reschedule {
+                    auction_id: parse_auction_id(auction_id)?,
+                    start_timestamp,
+                    end_timestamp
+                })
+            }
+            ("close-now", [auction_id]) => {
+                Ok(CliCommand::CloseNow { auction_id: parse_auction_id(auction_id)? })
+            }
+            _ => Err("Unknown command. Usage: create \"<item>\" <start> (<end> | for <duration>) [--starting-price <v>] [--min-increment <v>] [--reserve-price <v>] [--currency BRL|USD|EUR] [--soft-close <secs>] [--kind english|sealed|vickrey|dutch] [--quantity <n>] [--pricing discriminatory|uniform] | list | cancel <id> | extend <id> <end> | reschedule <id> <start> (<end> | for <duration>) | close-now <id>. Times: now, +5m, in 2h, 14:30, 2025-06-01 14:30".to_string()),
         }
     }
     
-    fn handle_notification(&mut self, message: String) {
-        self.messages.push(message);
-    }
-
-    async fn send_new_auction(&mut self, cmd: CliCommand, new_auction_tx: &Sender<Auction>){
-        let new_auction = cmd.to_auction(self.auctions.len() as u32  + 1).unwrap();
-        self.auctions.push(new_auction.clone());
+    async fn send_schedule_command(&mut self, cmd: CliCommand, schedule_tx: &Sender<ScheduleCommand>){
+        let command = match cmd.to_schedule_command() {
+            Ok(command) => command,
+            Err(e) => {
+                self.messages.push(format!("Error: {}\n", e));
+                return;
+            }
+        };
         
-        new_auction_tx
-            .send(new_auction)
+        schedule_tx
+            .send(command)
             .await
             .unwrap();
     }
-}
\ No newline at end of file
+}
+
+/// Parses the `--starting-price`, `--min-increment`, `--reserve-price`,
+/// `--currency`, `--soft-close`, `--kind`, `--quantity` and `--pricing` options
+/// of `create`.
+fn parse_create_options(options: &[String]) -> Result<AuctionOptions, String> {
+    let mut parsed = AuctionOptions::default();
+
+    // prices without a currency are in the one of the auction, wherever it is given
+    if let Some([_, currency]) = options.chunks(2).find(|option| option[0].eq_ignore_ascii_case("--currency")) {
+        parsed.currency = currency.parse().map_err(|e| format!("Invalid currency: {e}"))?;
+    }
+
+    for option in options.chunks(2) {
+        let [name, value] = option else {
+            return Err(format!("Missing value for {}", option[0]));
+        };
+        if name.eq_ignore_ascii_case("--currency") {
+            continue;
+        }
+        if name.eq_ignore_ascii_case("--kind") {
+            parsed.kind = match value.to_ascii_lowercase().as_str() {
+                "english" => AuctionKind::English,
+                "sealed" => AuctionKind::SealedFirstPrice,
+                "vickrey" => AuctionKind::SealedSecondPrice,
+                "dutch" => AuctionKind::Dutch,
+                _ => return Err(format!("Unknown auction kind '{value}', use english, sealed, vickrey or dutch")),
+            };
+            continue;
+        }
+        if name.eq_ignore_ascii_case("--pricing") {
+            parsed.unit_pricing = match value.to_ascii_lowercase().as_str() {
+                "discriminatory" => UnitPricing::Discriminatory,
+                "uniform" => UnitPricing::Uniform,
+                _ => return Err(format!("Unknown pricing '{value}', use discriminatory or uniform")),
+            };
+            continue;
+        }
+        if name.eq_ignore_ascii_case("--quantity") {
+            parsed.quantity = value.parse()
+                .ok()
+                .filter(|quantity| *quantity > 0)
+                .ok_or_else(|| format!("Invalid quantity '{value}', must be a positive integer"))?;
+            continue;
+        }
+
+        if name.eq_ignore_ascii_case("--soft-close") {
+            parsed.soft_close_secs = Some(value.parse()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .ok_or("The soft close window must be a whole number of seconds")?);
+            continue;
+        }
+
+        let value = Money::parse(value, parsed.currency)
+            .map_err(|e| format!("Invalid value '{value}' for {name}: {e}"))?;
+
+        match name.to_ascii_lowercase().as_str() {
+            "--starting-price" => parsed.pricing.starting_price = Some(value),
+            "--min-increment" if value.cents() > 0 => parsed.pricing.min_increment = Some(value),
+            "--min-increment" => return Err("The minimum increment must be above zero".to_string()),
+            "--reserve-price" => parsed.pricing.reserve_price = Some(value),
+            _ => return Err(format!("Unknown option {name}")),
+        }
+    }
+
+    parsed.validate()?;
+    Ok(parsed)
+}
+
+fn parse_auction_id(auction_id: &str) -> Result<u32, String> {
+    auction_id.parse().map_err(|_| "Invalid auction ID".to_string())
+}
+
+fn format_timestamp(timestamp: u128) -> String {
+    chrono::Local
+        .timestamp_millis_opt(timestamp as i64)
+        .single()
+        .map(|time| time.format("%d/%m/%Y %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
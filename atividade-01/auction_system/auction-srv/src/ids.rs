@@ -0,0 +1,165 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::storage::StoredAuction;
+
+/// Hands out auction ids that are never reused, across restarts and across
+/// auction-srv instances.
+///
+/// Instance `i` of `n` only uses the ids `k * n + i + 1`, so instances never
+/// collide as long as each one gets its own `AUCTION_SRV_INSTANCE`. The next `k`
+/// is written to disk before the id is handed out.
+pub struct IdAllocator {
+    path: Option<PathBuf>,
+    instance: u32,
+    instances: u32,
+    next_sequence: u32,
+}
+
+impl IdAllocator {
+    /// Builds the allocator from `AUCTION_SRV_INSTANCE` (default 0) and `AUCTION_SRV_IDS`,
+    /// the counter file, or `memory` to keep it in RAM. `instances` comes from the
+    /// broker config, which the other services use to find the owner of an auction.
+    pub fn open_default(stored_auctions: &[StoredAuction], instances: u32) -> io::Result<Self> {
+        let instance = env_number("AUCTION_SRV_INSTANCE", 0)?;
+        let location = std::env::var("AUCTION_SRV_IDS")
+            .unwrap_or_else(|_| "auction-srv/data/next_id".to_string());
+
+        let path = (location != "memory").then(|| PathBuf::from(location));
+        Self::open(path, instance, instances, stored_auctions)
+    }
+
+    pub fn open(
+        path: Option<PathBuf>,
+        instance: u32,
+        instances: u32,
+        stored_auctions: &[StoredAuction]
+    ) -> io::Result<Self> {
+        if instances == 0 || instance >= instances {
+            return Err(invalid(format!("instance {instance} is not one of {instances} instances")));
+        }
+
+        let persisted = match &path {
+            Some(path) => read_sequence(path)?,
+            None => 0,
+        };
+        // the store never goes backwards, so it covers a lost or older counter file
+        let after_stored = stored_auctions
+            .iter()
+            .map(|stored| stored.auction.id)
+            .filter(|id| *id > 0 && (id - 1) % instances == instance)
+            .map(|id| (id - 1) / instances + 1)
+            .max()
+            .unwrap_or(0);
+
+        Ok(IdAllocator {
+            path,
+            instance,
+            instances,
+            next_sequence: persisted.max(after_stored),
+        })
+    }
+
+    /// This instance's index, which picks the queues it consumes.
+    pub fn instance(&self) -> u32 {
+        self.instance
+    }
+
+    pub fn allocate(&mut self) -> io::Result<u32> {
+        let id = self.next_sequence
+            .checked_mul(self.instances)
+            .and_then(|id| id.checked_add(self.instance + 1))
+            .ok_or_else(|| invalid("auction ids exhausted".to_string()))?;
+
+        if let Some(path) = &self.path {
+            write_sequence(path, self.next_sequence + 1)?;
+        }
+        self.next_sequence += 1;
+
+        Ok(id)
+    }
+}
+
+fn env_number(var: &str, default: u32) -> io::Result<u32> {
+    match std::env::var(var) {
+        Ok(value) => value.parse().map_err(|_| invalid(format!("{var} must be a number, got '{value}'"))),
+        Err(_) => Ok(default),
+    }
+}
+
+fn read_sequence(path: &Path) -> io::Result<u32> {
+    match fs::read_to_string(path) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .map_err(|_| invalid(format!("corrupt id counter in {}", path.display()))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_sequence(path: &Path, sequence: u32) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    write!(tmp, "{sequence}")?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::models::Auction;
+    use shared::topology::{auction_srv_instance, auction_srv_queue, ENCERRAMENTO_SOLICITADO, PRORROGACAO_SOLICITADA};
+
+    use crate::storage::AuctionState;
+
+    #[test]
+    fn instances_never_share_ids_and_counters_survive_restarts() {
+        let path = std::env::temp_dir().join(format!("auction-ids-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut first = IdAllocator::open(Some(path.clone()), 0, 2, &[]).unwrap();
+        let mut second = IdAllocator::open(None, 1, 2, &[]).unwrap();
+        assert_eq!(first.allocate().unwrap(), 1);
+        assert_eq!(second.allocate().unwrap(), 2);
+        assert_eq!(first.allocate().unwrap(), 3);
+        drop(first);
+
+        let mut first = IdAllocator::open(Some(path.clone()), 0, 2, &[]).unwrap();
+        assert_eq!(first.allocate().unwrap(), 5);
+
+        // a stored auction newer than the counter file wins
+        let stored = [StoredAuction::new(Auction::new(9, "lamp".to_string(), 0, 1), AuctionState::Scheduled)];
+        let mut first = IdAllocator::open(Some(path.clone()), 0, 2, &stored).unwrap();
+        assert_eq!(first.allocate().unwrap(), 11);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn requests_about_an_auction_reach_the_instance_that_owns_it() {
+        let mut first = IdAllocator::open(None, 0, 2, &[]).unwrap();
+        let mut second = IdAllocator::open(None, 1, 2, &[]).unwrap();
+
+        for _ in 0..3 {
+            let id = first.allocate().unwrap();
+            assert_eq!(auction_srv_queue(PRORROGACAO_SOLICITADA, auction_srv_instance(id, 2)), "prorrogacao_solicitada.0");
+            assert_eq!(auction_srv_queue(ENCERRAMENTO_SOLICITADO, auction_srv_instance(id, 2)), "encerramento_solicitado.0");
+            let id = second.allocate().unwrap();
+            assert_eq!(auction_srv_queue(PRORROGACAO_SOLICITADA, auction_srv_instance(id, 2)), "prorrogacao_solicitada.1");
+            assert_eq!(auction_srv_queue(ENCERRAMENTO_SOLICITADO, auction_srv_instance(id, 2)), "encerramento_solicitado.1");
+        }
+    }
+}
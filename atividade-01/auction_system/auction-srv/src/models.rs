@@ -1,33 +1,179 @@
-use shared::models::Auction;
+use shared::models::{Auction, AuctionKind, Currency, Pricing, UnitPricing};
+
+use crate::storage::AuctionState;
 
 
 pub enum CliCommand{
     CreateAuction{
         item: String,
         start_timestamp: u64,
-        end_timestamp: u64
+        end_timestamp: u64,
+        options: AuctionOptions
     },
     ListAuctions,
+    Cancel{
+        auction_id: u32
+    },
+    Extend{
+        auction_id: u32,
+        end_timestamp: u64
+    },
+    Reschedule{
+        auction_id: u32,
+        start_timestamp: u64,
+        end_timestamp: u64
+    },
+    CloseNow{
+        auction_id: u32
+    },
 }
 
 impl CliCommand{
-    pub fn destructure(self) -> Option<(String, u64, u64)>{
+    /// The operator commands that change the schedule, as sent to the cron.
+    pub fn to_schedule_command(self) -> Result<ScheduleCommand, String>{
         match self {
-            Self::ListAuctions => None,
-            Self::CreateAuction { item, start_timestamp, end_timestamp } => Some((item, start_timestamp, end_timestamp))
-        }
-    }
-
-    pub fn to_auction(self, id: u32) -> Result<Auction, String>{
-        if let Some((item, start_timestamp, end_timestamp)) = self.destructure(){
-            return Ok(Auction{
-                id,
+            Self::CreateAuction { item, start_timestamp, end_timestamp, options } => Ok(ScheduleCommand::Create {
                 item,
                 start_timestamp: start_timestamp as u128,
                 end_timestamp: end_timestamp as u128,
-                status: true
-            })
+                options
+            }),
+            Self::Cancel { auction_id } => Ok(ScheduleCommand::Cancel { auction_id }),
+            Self::Extend { auction_id, end_timestamp } => Ok(ScheduleCommand::Extend {
+                auction_id,
+                end_timestamp: end_timestamp as u128
+            }),
+            Self::Reschedule { auction_id, start_timestamp, end_timestamp } => Ok(ScheduleCommand::Reschedule {
+                auction_id,
+                start_timestamp: start_timestamp as u128,
+                end_timestamp: end_timestamp as u128
+            }),
+            Self::CloseNow { auction_id } => Ok(ScheduleCommand::CloseNow { auction_id }),
+            Self::ListAuctions => Err("Listing auctions does not change the schedule".to_string()),
+        }
+    }
+}
+
+/// The optional settings of `create`.
+#[derive(Clone, Copy, Debug)]
+pub struct AuctionOptions{
+    pub pricing: Pricing,
+    pub soft_close_secs: Option<u64>,
+    pub kind: AuctionKind,
+    pub quantity: u32,
+    pub unit_pricing: UnitPricing,
+    pub currency: Currency
+}
+
+impl Default for AuctionOptions{
+    fn default() -> Self{
+        AuctionOptions {
+            pricing: Pricing::default(),
+            soft_close_secs: None,
+            kind: AuctionKind::default(),
+            quantity: 1,
+            unit_pricing: UnitPricing::default(),
+            currency: Currency::default()
+        }
+    }
+}
+
+impl AuctionOptions{
+    /// Rejects settings that make no sense for the kind of auction.
+    pub fn validate(&self) -> Result<(), String>{
+        if self.kind == AuctionKind::Dutch && self.pricing.starting_price.is_none() {
+            return Err("A Dutch auction needs a --starting-price to count down from".to_string());
+        }
+        if self.kind != AuctionKind::English && self.pricing.min_increment.is_some() {
+            return Err("--min-increment only applies to english auctions".to_string());
+        }
+        // an extension would tell everyone a late bid came in
+        if self.kind != AuctionKind::English && self.soft_close_secs.is_some() {
+            return Err("--soft-close only applies to english auctions".to_string());
         }
-        Err("Cannot create auction from command".to_string())
+        let prices = [self.pricing.starting_price, self.pricing.min_increment, self.pricing.reserve_price];
+        if prices.into_iter().flatten().any(|price| price.currency() != self.currency) {
+            return Err(format!("Prices must be in the currency of the auction, {}", self.currency));
+        }
+        // the first bid takes the lot at the current price
+        if self.kind == AuctionKind::Dutch && self.quantity > 1 {
+            return Err("A Dutch auction sells a single unit".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn apply(self, auction: Auction) -> Auction{
+        auction
+            .with_pricing(self.pricing)
+            .with_soft_close(self.soft_close_secs)
+            .with_kind(self.kind)
+            .with_units(self.quantity, self.unit_pricing)
+            .with_currency(self.currency)
     }
-}
\ No newline at end of file
+}
+
+/// Changes to the schedule, applied by `task_cron`.
+#[derive(Clone, Debug)]
+pub enum ScheduleCommand{
+    /// The cron assigns the id, so it is unique however many consoles send these.
+    Create{
+        item: String,
+        start_timestamp: u128,
+        end_timestamp: u128,
+        options: AuctionOptions
+    },
+    /// Drops a scheduled auction, or ends an open one without a winner.
+    Cancel{
+        auction_id: u32
+    },
+    /// Moves the end of a scheduled or open auction.
+    Extend{
+        auction_id: u32,
+        end_timestamp: u128
+    },
+    /// Moves both ends of an auction that has not started yet.
+    Reschedule{
+        auction_id: u32,
+        start_timestamp: u128,
+        end_timestamp: u128
+    },
+    /// Pushes back the end of an open auction after a bid in its soft-close
+    /// window, as asked by bid-srv. Never brings the end forward.
+    SoftClose{
+        auction_id: u32,
+        end_timestamp: u128
+    },
+    /// Ends an open auction on the next tick, as if its time was up.
+    CloseNow{
+        auction_id: u32
+    },
+}
+
+/// Events published by auction-srv. The auction is persisted in `state()` once the
+/// broker confirmed the event.
+#[derive(Clone, Debug)]
+pub enum LifecycleEvent{
+    Started(Auction),
+    Finished(Auction),
+    Cancelled(Auction),
+    Extended(Auction),
+}
+
+impl LifecycleEvent{
+    pub fn auction(&self) -> &Auction{
+        match self {
+            Self::Started(auction)
+            | Self::Finished(auction)
+            | Self::Cancelled(auction)
+            | Self::Extended(auction) => auction
+        }
+    }
+
+    pub fn state(&self) -> AuctionState{
+        match self {
+            Self::Started(_) | Self::Extended(_) => AuctionState::Started,
+            Self::Finished(_) => AuctionState::Finished,
+            Self::Cancelled(_) => AuctionState::Cancelled,
+        }
+    }
+}
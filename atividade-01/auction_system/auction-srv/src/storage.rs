@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use shared::models::Auction;
+
+/*==================================================== MODELS ====================================================*/
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum AuctionState {
+    Scheduled,
+    Started,
+    Finished,
+    Cancelled,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct StoredAuction {
+    pub auction: Auction,
+    pub state: AuctionState,
+}
+
+impl StoredAuction {
+    pub fn new(auction: Auction, state: AuctionState) -> Self {
+        StoredAuction { auction, state }
+    }
+}
+
+/*==================================================== STORE ====================================================*/
+
+/// Storage backend used by `task_cron` to keep auctions across restarts.
+pub trait AuctionStore: Send {
+    /// Returns every stored auction, ordered by id.
+    fn load(&mut self) -> io::Result<Vec<StoredAuction>>;
+
+    /// Inserts the auction or replaces the previous record with the same id.
+    fn save(&mut self, stored: &StoredAuction) -> io::Result<()>;
+}
+
+/// Builds the store selected by the `AUCTION_SRV_STORE` environment variable:
+/// `memory` keeps everything in RAM, anything else is used as the log file path.
+pub fn open_store() -> io::Result<Box<dyn AuctionStore>> {
+    let location = std::env::var("AUCTION_SRV_STORE")
+        .unwrap_or_else(|_| "auction-srv/data/auctions.jsonl".to_string());
+
+    if location == "memory" {
+        return Ok(Box::new(MemoryAuctionStore::default()));
+    }
+
+    Ok(Box::new(FileAuctionStore::open(location)?))
+}
+
+/// Keeps auctions only for the lifetime of the process.
+#[derive(Default)]
+pub struct MemoryAuctionStore {
+    auctions: HashMap<u32, StoredAuction>,
+}
+
+impl AuctionStore for MemoryAuctionStore {
+    fn load(&mut self) -> io::Result<Vec<StoredAuction>> {
+        let mut auctions: Vec<StoredAuction> = self.auctions.values().cloned().collect();
+        auctions.sort_by_key(|s| s.auction.id);
+        Ok(auctions)
+    }
+
+    fn save(&mut self, stored: &StoredAuction) -> io::Result<()> {
+        self.auctions.insert(stored.auction.id, stored.clone());
+        Ok(())
+    }
+}
+
+/// Append-only JSON lines log, one record per change. The latest record of
+/// each id wins, and the log is compacted every time it is loaded.
+pub struct FileAuctionStore {
+    path: PathBuf,
+    file: File,
+}
+
+impl FileAuctionStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(FileAuctionStore { path, file })
+    }
+
+    fn compact(&mut self, auctions: &[StoredAuction]) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        for stored in auctions {
+            serde_json::to_writer(&mut tmp, stored)?;
+            tmp.write_all(b"\n")?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        sync_parent_dir(&self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Flushes the directory entry of `path`, so a rename survives a crash.
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+impl AuctionStore for FileAuctionStore {
+    fn load(&mut self) -> io::Result<Vec<StoredAuction>> {
+        let mut latest: HashMap<u32, StoredAuction> = HashMap::new();
+        let reader = BufReader::new(File::open(&self.path)?);
+
+        let mut lines = reader.lines().enumerate().peekable();
+        while let Some((line_number, line)) = lines.next() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<StoredAuction>(&line) {
+                Ok(stored) => {
+                    latest.insert(stored.auction.id, stored);
+                }
+                // a crash mid-write only tears the last line, compacting drops it
+                Err(e) if lines.peek().is_none() => {
+                    println!("Skipping torn record at line {} of {}: {e}", line_number + 1, self.path.display());
+                }
+                // anything else is damage compacting would make permanent
+                Err(e) => return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt record at line {} of {}: {e}", line_number + 1, self.path.display())
+                )),
+            }
+        }
+
+        let mut auctions: Vec<StoredAuction> = latest.into_values().collect();
+        auctions.sort_by_key(|s| s.auction.id);
+        self.compact(&auctions)?;
+
+        Ok(auctions)
+    }
+
+    fn save(&mut self, stored: &StoredAuction) -> io::Result<()> {
+        let mut line = serde_json::to_vec(stored)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store_keeps_latest_state_across_reopen() {
+        let path = std::env::temp_dir().join(format!("auction-store-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let auction = Auction::new(7, "lamp".to_string(), 10, 20);
+        let mut store = FileAuctionStore::open(&path).unwrap();
+        store.save(&StoredAuction::new(auction.clone(), AuctionState::Scheduled)).unwrap();
+        store.save(&StoredAuction::new(auction, AuctionState::Started)).unwrap();
+        drop(store);
+
+        let mut store = FileAuctionStore::open(&path).unwrap();
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].auction.id, 7);
+        assert_eq!(loaded[0].state, AuctionState::Started);
+        drop(store);
+
+        // a torn last line is dropped, a corrupt line before it stops the load
+        let compacted = fs::read_to_string(&path).unwrap();
+        fs::write(&path, format!("{compacted}{{\"auction\":")).unwrap();
+        assert_eq!(FileAuctionStore::open(&path).unwrap().load().unwrap().len(), 1);
+        fs::write(&path, format!("{{\"auction\":\n{compacted}")).unwrap();
+        assert!(FileAuctionStore::open(&path).unwrap().load().is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
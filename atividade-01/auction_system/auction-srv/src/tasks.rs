@@ -1,153 +1,672 @@
-use tokio::sync::{mpsc::{Receiver, Sender}};
-use lapin::{options::BasicPublishOptions, BasicProperties, Connection};
+use tokio::sync::{mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender}, watch};
+use std::{cmp::Reverse, collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, VecDeque}, sync::Arc};
+use lapin::options::BasicAckOptions;
 
-use std::{sync::Arc, time::{Duration, SystemTime}};
+use std::time::{Duration, SystemTime};
 
 use shared::models::{
-    Auction
+    Auction,
+    AuctionCancelled,
+    AuctionExtended,
+    AuctionFinished,
+    AuctionStatus,
+    AuctionSummary,
+    Envelope,
+    MessageType,
+    Money,
+    Notification,
+    NotificationType,
+    Query,
+    QueryReply
+};
+use shared::broker::{Broker, PendingConfirm, Publisher};
+use shared::rpc::serve;
+use shared::topology::{
+    auction_srv_queue,
+    AUCTION_SRV_NOTIFICACOES,
+    CONSULTA_LEILOES,
+    ENCERRAMENTO_SOLICITADO,
+    LEILAO_CANCELADO,
+    LEILAO_FINALIZADO,
+    LEILAO_INICIADO,
+    LEILAO_PRORROGADO,
+    PRORROGACAO_SOLICITADA
 };
 use crate::cli::Cli;
+use crate::ids::IdAllocator;
+use crate::models::{LifecycleEvent, ScheduleCommand};
+use crate::storage::{AuctionState, AuctionStore, StoredAuction};
 
 pub async fn task_cli(
-    new_auction_tx: Sender<Auction>,
+    schedule_tx: Sender<ScheduleCommand>,
+    cli_print_rx: Receiver<String>,
     cli: Cli
 ){
-    cli.run(new_auction_tx).await.unwrap();
+    cli.run(schedule_tx, cli_print_rx).await.unwrap();
 }
 
-pub async fn task_publish_auction_start(
-    conn: Arc<Connection>,
-    mut started_auction_rx: Receiver<Auction>
+/// Publishes every lifecycle event of the auctions. They all go through the same
+/// publisher, so their confirmations come back in the order the cron sent them.
+pub async fn task_publish_lifecycle(
+    publisher: Publisher,
+    mut lifecycle_rx: Receiver<LifecycleEvent>,
+    pending_tx: UnboundedSender<(LifecycleEvent, PendingConfirm)>
 ){
-    let channel = conn.create_channel().await.unwrap();
+    while let Some(event) = lifecycle_rx.recv().await{
+        let pending = publish_lifecycle_event(&publisher, &event).unwrap();
+        if pending_tx.send((event, pending)).is_err(){
+            break;
+        }
+    }
+}
 
-    while let Some(auction) = started_auction_rx.recv().await{
-        let payload = serde_json::to_vec(&auction).unwrap();
-        channel
-            .basic_publish(
-                "leilao_iniciado",
-                "", 
-                BasicPublishOptions::default(),
-                payload.as_slice(),
-                BasicProperties::default()
-            ).await.unwrap();
+/// Waits for the broker to confirm each lifecycle event, in order, and only then
+/// hands it back to the cron to be persisted.
+pub async fn task_confirm_lifecycle(
+    mut pending_rx: UnboundedReceiver<(LifecycleEvent, PendingConfirm)>,
+    confirmed_tx: Sender<LifecycleEvent>
+){
+    while let Some((event, pending)) = pending_rx.recv().await{
+        if !pending.confirmed().await{
+            eprintln!("Publisher stopped before {:?} was confirmed", event);
+            break;
+        }
+        if confirmed_tx.send(event).await.is_err(){
+            break;
+        }
     }
 }
 
-pub async fn task_publish_auction_finish(
-    conn: Arc<Connection>,
-    mut finished_auction_rx: Receiver<Auction>
+/// Answers `list` and `get-auction` queries from the cron's latest view. Clients
+/// ask every instance for the list, and only the owner for a single auction.
+pub async fn task_serve_queries(
+    broker: Arc<Broker>,
+    instance: u32,
+    auctions_rx: watch::Receiver<BTreeMap<u32, AuctionSummary>>
 ){
-    let channel = conn.create_channel().await.unwrap();
+    serve(broker, &auction_srv_queue(CONSULTA_LEILOES, instance), "auction-srv", |query| {
+        let reply = answer_query(&auctions_rx.borrow(), query);
+        async move { reply }
+    }).await;
+}
 
-    while let Some(auction) = finished_auction_rx.recv().await{
-        let payload = auction.id.to_ne_bytes();
-        channel
-            .basic_publish(
-                "",
-                "leilao_finalizado",
-                BasicPublishOptions::default(),
-                payload.as_slice(),
-                BasicProperties::default()
-            ).await.unwrap();
+fn answer_query(auctions: &BTreeMap<u32, AuctionSummary>, query: Query) -> QueryReply{
+    match query {
+        Query::ListAuctions => QueryReply::Auctions(auctions.values().cloned().collect()),
+        Query::GetAuction { auction_id } => QueryReply::Auction(auctions.get(&auction_id).cloned()),
+        _ => QueryReply::Unsupported,
     }
 }
 
-pub async fn task_cron(
-    live_auctions: Vec<Auction>,
-    mut new_auction_rx: Receiver<Auction>,
-    mut started_auction_tx: Sender<Auction>,
-    mut finished_auction_tx: Sender<Auction>
+/// Keeps the highest bid of each auction, as announced on `notificacoes`, for the console.
+pub async fn task_track_high_bids(
+    broker: Arc<Broker>,
+    high_bids_tx: watch::Sender<HashMap<u32, Money>>
 ){
-    let mut scheduled_auctions = live_auctions;
-    let mut finished_auctions: Vec<Auction> = Vec::with_capacity(scheduled_auctions.len());
-    let mut started_auctions: Vec<Auction> = Vec::with_capacity(scheduled_auctions.len());
+    let mut deliveries = broker.subscribe(AUCTION_SRV_NOTIFICACOES, "auction-srv");
+
     loop{
-        if let Ok(auction) = new_auction_rx.try_recv(){
-            scheduled_auctions.push(auction)
+        let (delivery, envelope) = deliveries
+            .next_envelope::<Notification>(MessageType::Notification)
+            .await;
+        let notification = envelope.payload;
+
+        // cancellations and extensions carry no bid
+        if matches!(
+            notification.get_notification_type(),
+            NotificationType::NewBid | NotificationType::AuctionWinner
+        ){
+            high_bids_tx.send_modify(|high_bids| {
+                let value = notification.get_bid_value();
+                let high_bid = high_bids.entry(notification.get_auction_id()).or_insert(value);
+                if value > *high_bid {
+                    *high_bid = value;
+                }
+            });
         }
 
-        let now = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_millis();
+        if let Err(e) = delivery.ack(BasicAckOptions::default()).await{
+            eprintln!("Failed to ack notification: {e}");
+        }
+    }
+}
 
-        if scheduled_auctions.iter().any(|auc| auc.start_timestamp <= now){
-            start_auctions(
-                &mut scheduled_auctions,
-                &mut started_auctions,
-                &mut started_auction_tx,
-                now
-            ).await;
+/// Turns the extensions bid-srv asks for into schedule commands. They are acked
+/// once the cron has them; the cron persists the new end once it is announced.
+/// bid-srv sends them to the queue of the instance that owns the auction.
+pub async fn task_receive_extensions(
+    broker: Arc<Broker>,
+    instance: u32,
+    schedule_tx: Sender<ScheduleCommand>
+){
+    let mut deliveries = broker.subscribe(&auction_srv_queue(PRORROGACAO_SOLICITADA, instance), "auction-srv");
+
+    loop{
+        let (delivery, envelope) = deliveries
+            .next_envelope::<AuctionExtended>(MessageType::ExtensionRequested)
+            .await;
+        let AuctionExtended { auction_id, end_timestamp } = envelope.payload;
+
+        if schedule_tx.send(ScheduleCommand::SoftClose { auction_id, end_timestamp }).await.is_err(){
+            break;
         }
 
-        if started_auctions.iter().any(|auc| auc.end_timestamp <= now){
-            finish_auctions(
-                &mut started_auctions,
-                &mut finished_auctions,
-                &mut finished_auction_tx,
-                now
-            ).await;
+        if let Err(e) = delivery.ack(BasicAckOptions::default()).await{
+            eprintln!("Failed to ack extension request, it will be redelivered: {e}");
+        }
+    }
+}
+
+/// Closes the Dutch auctions that took their bid, as asked by bid-srv on the
+/// queue of the instance that owns the auction.
+pub async fn task_receive_close_requests(
+    broker: Arc<Broker>,
+    instance: u32,
+    schedule_tx: Sender<ScheduleCommand>
+){
+    let mut deliveries = broker.subscribe(&auction_srv_queue(ENCERRAMENTO_SOLICITADO, instance), "auction-srv");
+
+    loop{
+        let (delivery, envelope) = deliveries
+            .next_envelope::<AuctionFinished>(MessageType::CloseRequested)
+            .await;
+        let auction_id = envelope.payload.auction_id;
+
+        if schedule_tx.send(ScheduleCommand::CloseNow { auction_id }).await.is_err(){
+            break;
         }
 
-        tokio::time::sleep(Duration::from_millis(300)).await;
+        if let Err(e) = delivery.ack(BasicAckOptions::default()).await{
+            eprintln!("Failed to ack close request, it will be redelivered: {e}");
+        }
     }
+}
 
+/// What the cron owns: where auctions and ids are kept, and its channels to the other tasks.
+pub struct CronContext{
+    pub store: Box<dyn AuctionStore>,
+    pub ids: IdAllocator,
+    pub schedule_rx: Receiver<ScheduleCommand>,
+    pub lifecycle_tx: Sender<LifecycleEvent>,
+    pub confirmed_rx: Receiver<LifecycleEvent>,
+    pub auctions_tx: watch::Sender<BTreeMap<u32, AuctionSummary>>,
+    pub cli_print_tx: Sender<String>
 }
 
-async fn start_auctions(
-    scheduled_auctions: &mut Vec<Auction>,
-    started_auctions: &mut Vec<Auction>,
-    started_auction_tx: &mut Sender<Auction>,
-    now: u128
+/// Sleeps until the next start or end is due, and wakes up right away for
+/// operator commands and confirmations.
+pub async fn task_cron(
+    stored_auctions: Vec<StoredAuction>,
+    context: CronContext
 ){
-    let auctions_to_start: Vec<Auction> = scheduled_auctions
-        .iter()
-        .filter(|a| a.start_timestamp <= now)
-        .cloned()
-        .collect();
-
-    let mut auctions_to_start_cloned = auctions_to_start.clone();
-    for auction in auctions_to_start{
-        started_auction_tx
-            .send(auction)
-            .await
-            .unwrap();
-    }
-
-    started_auctions.append(&mut auctions_to_start_cloned);
-    *scheduled_auctions = scheduled_auctions
-        .iter()
-        .filter(|a| a.start_timestamp > now)
-        .cloned()
-        .collect();
-}
-
-async fn finish_auctions(
-    started_auctions: &mut Vec<Auction>,
-    ended_auctions: &mut Vec<Auction>,
-    finished_auction_tx: &mut Sender<Auction>,
+    let CronContext {
+        mut store,
+        mut ids,
+        mut schedule_rx,
+        lifecycle_tx,
+        mut confirmed_rx,
+        auctions_tx,
+        cli_print_tx
+    } = context;
+
+    // auctions whose start or end passed while we were down are due right away,
+    // and go out in the same order as usual; long finished ones only stay in the store
+    let mut schedule = Schedule::default();
+    let now = now_millis();
+    for stored in stored_auctions{
+        let id = stored.auction.id;
+        match stored.state {
+            AuctionState::Finished | AuctionState::Cancelled => {
+                if stored.auction.end_timestamp + FINISHED_RETENTION_MS < now{
+                    continue;
+                }
+                schedule.insert(stored.auction, stored.state);
+                schedule.forget_later(id, now);
+            }
+            _ => schedule.insert(stored.auction, stored.state),
+        }
+    }
+
+    loop{
+        auctions_tx.send_if_modified(|view| schedule.update_view(view));
+
+        // an hour is only a cap, anything new wakes the loop earlier
+        let until_next_due = schedule
+            .next_due()
+            .map(|due| Duration::from_millis(due.saturating_sub(now_millis()) as u64))
+            .unwrap_or(Duration::from_secs(3600));
+
+        tokio::select! {
+            Some(command) = schedule_rx.recv() => {
+                // a burst of commands is applied before the view is updated
+                let mut next = Some(command);
+                while let Some(command) = next{
+                    let result = apply_command(
+                        command,
+                        &mut schedule,
+                        store.as_mut(),
+                        &mut ids,
+                        &lifecycle_tx,
+                        now_millis()
+                    ).await;
+                    let message = match result {
+                        Ok(message) => message,
+                        Err(e) => format!("Error: {e}"),
+                    };
+                    if let Err(e) = cli_print_tx.send(format!("{message}\n")).await{
+                        eprintln!("Failed to send message to CLI: {e}");
+                    }
+                    next = schedule_rx.try_recv().ok();
+                }
+            }
+            // auctions are only stored as started/finished once the broker has the event;
+            // until then a restart publishes it again under the same message id
+            Some(event) = confirmed_rx.recv() => {
+                persist(store.as_mut(), event.auction(), event.state());
+            }
+            _ = tokio::time::sleep(until_next_due) => {}
+        }
+
+        schedule.fire_due(now_millis(), &lifecycle_tx).await;
+    }
+
+}
+
+/// How long a finished or cancelled auction stays in memory, for the console and
+/// queries, before only the store has it.
+const FINISHED_RETENTION_MS: u128 = 60 * 60 * 1000;
+
+/// The cron's view of every auction, with a min-heap of the upcoming starts and ends.
+///
+/// Changing a start or end pushes a new entry instead of updating the old one;
+/// entries that no longer match the auction are dropped when they come up.
+#[derive(Default)]
+struct Schedule{
+    auctions: BTreeMap<u32, (Auction, AuctionState)>,
+    due: BinaryHeap<Reverse<(u128, u32)>>,
+    /// Finished and cancelled auctions, in the order they are dropped from memory.
+    forget: VecDeque<(u128, u32)>,
+    /// Auctions changed since the last [`Schedule::update_view`].
+    changed: BTreeSet<u32>
+}
+
+impl Schedule{
+    fn insert(&mut self, auction: Auction, state: AuctionState){
+        let id = auction.id;
+        self.auctions.insert(id, (auction, state));
+        self.changed.insert(id);
+        self.wake_at(id);
+    }
+
+    /// Drops a finished or cancelled auction from memory `FINISHED_RETENTION_MS` after `now`.
+    fn forget_later(&mut self, auction_id: u32, now: u128){
+        self.forget.push_back((now + FINISHED_RETENTION_MS, auction_id));
+    }
+
+    /// Queues the next start or end of the auction, given its current state.
+    fn wake_at(&mut self, auction_id: u32){
+        if let Some((auction, state)) = self.auctions.get(&auction_id){
+            let due = match state {
+                AuctionState::Scheduled => auction.start_timestamp,
+                AuctionState::Started => auction.end_timestamp,
+                AuctionState::Finished | AuctionState::Cancelled => return,
+            };
+            self.due.push(Reverse((due, auction_id)));
+        }
+    }
+
+    fn next_due(&self) -> Option<u128>{
+        let forget_at = self.forget.front().map(|(at, _)| *at);
+        self.due.peek().map(|Reverse((due, _))| *due).into_iter().chain(forget_at).min()
+    }
+
+    /// Starts and finishes every auction due by `now`, oldest first, and forgets
+    /// the ones that finished long enough ago.
+    async fn fire_due(&mut self, now: u128, lifecycle_tx: &Sender<LifecycleEvent>){
+        while let Some(Reverse((due, auction_id))) = self.due.peek().copied()
+            && due <= now {
+            self.due.pop();
+
+            let Some((auction, state)) = self.auctions.get_mut(&auction_id) else {
+                continue;
+            };
+            let event = match state {
+                AuctionState::Scheduled if auction.start_timestamp == due => {
+                    *state = AuctionState::Started;
+                    LifecycleEvent::Started(auction.clone())
+                }
+                AuctionState::Started if auction.end_timestamp == due => {
+                    *state = AuctionState::Finished;
+                    self.forget.push_back((now + FINISHED_RETENTION_MS, auction_id));
+                    LifecycleEvent::Finished(auction.clone())
+                }
+                // stale entry, the auction was moved or already left that state
+                _ => continue,
+            };
+
+            lifecycle_tx.send(event).await.unwrap();
+            self.changed.insert(auction_id);
+            self.wake_at(auction_id);
+        }
+
+        while let Some((forget_at, auction_id)) = self.forget.front().copied()
+            && forget_at <= now {
+            self.forget.pop_front();
+            self.auctions.remove(&auction_id);
+            self.changed.insert(auction_id);
+        }
+    }
+
+    /// Brings `view` up to date with the auctions changed since the last call.
+    /// Returns whether anything changed.
+    fn update_view(&mut self, view: &mut BTreeMap<u32, AuctionSummary>) -> bool{
+        let changed = std::mem::take(&mut self.changed);
+        for auction_id in &changed{
+            match self.auctions.get(auction_id) {
+                Some((auction, state)) => {
+                    view.insert(*auction_id, summary(auction, *state));
+                }
+                None => {
+                    view.remove(auction_id);
+                }
+            }
+        }
+
+        !changed.is_empty()
+    }
+
+    fn state_of(&self, auction_id: u32) -> Option<AuctionState>{
+        self.auctions.get(&auction_id).map(|(_, state)| *state)
+    }
+
+    fn auction_mut(&mut self, auction_id: u32) -> &mut Auction{
+        self.changed.insert(auction_id);
+        &mut self.auctions.get_mut(&auction_id).expect("checked by state_of").0
+    }
+
+    fn set_state(&mut self, auction_id: u32, new_state: AuctionState){
+        if let Some((_, state)) = self.auctions.get_mut(&auction_id){
+            *state = new_state;
+            self.changed.insert(auction_id);
+        }
+    }
+}
+
+fn summary(auction: &Auction, state: AuctionState) -> AuctionSummary{
+    AuctionSummary {
+        auction: auction.clone(),
+        status: match state {
+            AuctionState::Scheduled => AuctionStatus::Scheduled,
+            AuctionState::Started => AuctionStatus::Open,
+            AuctionState::Finished => AuctionStatus::Closed,
+            AuctionState::Cancelled => AuctionStatus::Cancelled,
+        }
+    }
+}
+
+/// Applies an operator command and returns the message shown on the console.
+/// Changes to auctions nobody heard of yet are persisted right away; changes to
+/// open auctions are published first and persisted once confirmed, like starts and ends.
+async fn apply_command(
+    command: ScheduleCommand,
+    schedule: &mut Schedule,
+    store: &mut dyn AuctionStore,
+    ids: &mut IdAllocator,
+    lifecycle_tx: &Sender<LifecycleEvent>,
     now: u128
-){
-    let auctions_to_finish: Vec<Auction> = started_auctions
-        .iter()
-        .filter(|a| a.end_timestamp <= now)
-        .cloned()
-        .collect();
-    
-    let mut auctions_to_finish_cloned = auctions_to_finish.clone();
-    for auction in auctions_to_finish{
-        finished_auction_tx
-            .send(auction)
-            .await
-            .unwrap();
-    }
-
-    ended_auctions.append(&mut auctions_to_finish_cloned);
-    *started_auctions = started_auctions
-        .iter()
-        .filter(|a| a.end_timestamp > now)
-        .cloned()
-        .collect();
-
-}
\ No newline at end of file
+) -> Result<String, String>{
+    match command {
+        ScheduleCommand::Create { item, start_timestamp, end_timestamp, options } => {
+            let id = ids.allocate().map_err(|e| format!("Failed to allocate an auction id: {e}"))?;
+            let auction = options.apply(Auction::new(id, item, start_timestamp, end_timestamp));
+            persist(store, &auction, AuctionState::Scheduled);
+            schedule.insert(auction, AuctionState::Scheduled);
+            Ok(format!("Auction {id} scheduled"))
+        }
+        ScheduleCommand::Cancel { auction_id } => match schedule.state_of(auction_id) {
+            Some(AuctionState::Scheduled) => {
+                schedule.set_state(auction_id, AuctionState::Cancelled);
+                schedule.forget_later(auction_id, now);
+                persist(store, schedule.auction_mut(auction_id), AuctionState::Cancelled);
+                Ok(format!("Auction {auction_id} cancelled before it started"))
+            }
+            Some(AuctionState::Started) => {
+                schedule.set_state(auction_id, AuctionState::Cancelled);
+                schedule.forget_later(auction_id, now);
+                let auction = schedule.auction_mut(auction_id).clone();
+                lifecycle_tx.send(LifecycleEvent::Cancelled(auction)).await.unwrap();
+                Ok(format!("Auction {auction_id} cancelled"))
+            }
+            state => Err(not_changeable(auction_id, state)),
+        },
+        ScheduleCommand::Extend { auction_id, end_timestamp } => {
+            if end_timestamp <= now {
+                return Err("The new end must be in the future".to_string());
+            }
+
+            match schedule.state_of(auction_id) {
+                Some(AuctionState::Scheduled) => {
+                    let auction = schedule.auction_mut(auction_id);
+                    if end_timestamp <= auction.start_timestamp {
+                        return Err("The new end must be after the start".to_string());
+                    }
+                    auction.end_timestamp = end_timestamp;
+                    persist(store, auction, AuctionState::Scheduled);
+                    Ok(format!("Auction {auction_id} now ends at {end_timestamp}"))
+                }
+                Some(AuctionState::Started) => {
+                    let auction = schedule.auction_mut(auction_id);
+                    // bidders were promised the current end, use close to end it sooner
+                    if end_timestamp <= auction.end_timestamp {
+                        return Err(format!("The new end must be after the current end, {}", auction.end_timestamp));
+                    }
+                    auction.end_timestamp = end_timestamp;
+                    let auction = auction.clone();
+                    schedule.wake_at(auction_id);
+                    lifecycle_tx.send(LifecycleEvent::Extended(auction)).await.unwrap();
+                    Ok(format!("Auction {auction_id} extended until {end_timestamp}"))
+                }
+                state => Err(not_changeable(auction_id, state)),
+            }
+        }
+        ScheduleCommand::Reschedule { auction_id, start_timestamp, end_timestamp } => {
+            if start_timestamp >= end_timestamp {
+                return Err("The start must be before the end".to_string());
+            }
+            // checked again here, the start may have passed while the command was queued
+            if start_timestamp + START_GRACE_MS < now {
+                return Err("The start cannot be in the past".to_string());
+            }
+
+            match schedule.state_of(auction_id) {
+                Some(AuctionState::Scheduled) => {
+                    let auction = schedule.auction_mut(auction_id);
+                    auction.start_timestamp = start_timestamp;
+                    auction.end_timestamp = end_timestamp;
+                    persist(store, auction, AuctionState::Scheduled);
+                    schedule.wake_at(auction_id);
+                    Ok(format!("Auction {auction_id} rescheduled"))
+                }
+                Some(AuctionState::Started) => Err(format!("Auction {auction_id} already started, use extend")),
+                state => Err(not_changeable(auction_id, state)),
+            }
+        }
+        ScheduleCommand::SoftClose { auction_id, end_timestamp } => match schedule.state_of(auction_id) {
+            Some(AuctionState::Started) if end_timestamp > schedule.auction_mut(auction_id).end_timestamp => {
+                schedule.auction_mut(auction_id).end_timestamp = end_timestamp;
+                let auction = schedule.auction_mut(auction_id).clone();
+                schedule.wake_at(auction_id);
+                lifecycle_tx.send(LifecycleEvent::Extended(auction)).await.unwrap();
+                Ok(format!("Late bid on auction {auction_id}, extended until {end_timestamp}"))
+            }
+            // the auction ended, or another late bid already pushed it further
+            _ => Ok(format!("Ignoring soft close extension of auction {auction_id}")),
+        },
+        ScheduleCommand::CloseNow { auction_id } => match schedule.state_of(auction_id) {
+            Some(AuctionState::Started) => {
+                // finished right after this command, through the usual path
+                schedule.auction_mut(auction_id).end_timestamp = now;
+                schedule.wake_at(auction_id);
+                Ok(format!("Closing auction {auction_id}"))
+            }
+            state => Err(not_changeable(auction_id, state)),
+        },
+    }
+}
+
+/// How long a start of `now` has to reach the cron before it counts as past.
+const START_GRACE_MS: u128 = 1_000;
+
+fn not_changeable(auction_id: u32, state: Option<AuctionState>) -> String{
+    match state {
+        Some(state) => format!("Auction {auction_id} is {state:?} and cannot be changed that way"),
+        None => format!("Auction {auction_id} not found"),
+    }
+}
+
+fn now_millis() -> u128{
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+fn persist(store: &mut dyn AuctionStore, auction: &Auction, state: AuctionState){
+    let stored = StoredAuction::new(auction.clone(), state);
+    if let Err(e) = store.save(&stored){
+        eprintln!("Failed to persist auction {} as {:?}: {e}", auction.id, state);
+    }
+}
+
+/*============================================= PUBLISH ============================================= */
+
+/// Lifecycle events get deterministic ids, so the copies published again after a
+/// restart or a lost confirmation can be told apart from new events downstream.
+fn publish_lifecycle_event(
+    publisher: &Publisher,
+    event: &LifecycleEvent
+) -> Result<PendingConfirm, serde_json::Error>{
+    match event {
+        LifecycleEvent::Started(auction) => {
+            let envelope = Envelope::new(MessageType::AuctionStarted, auction)
+                .with_message_id(format!("auction-{}-started", auction.id));
+            publisher.publish_envelope(LEILAO_INICIADO, "", &envelope)
+        }
+        LifecycleEvent::Finished(auction) => {
+            let envelope = Envelope::new(
+                MessageType::AuctionFinished,
+                AuctionFinished { auction_id: auction.id }
+            ).with_message_id(format!("auction-{}-finished", auction.id));
+            publisher.publish_envelope("", LEILAO_FINALIZADO, &envelope)
+        }
+        LifecycleEvent::Cancelled(auction) => {
+            let envelope = Envelope::new(
+                MessageType::AuctionCancelled,
+                AuctionCancelled { auction_id: auction.id }
+            ).with_message_id(format!("auction-{}-cancelled", auction.id));
+            publisher.publish_envelope(LEILAO_CANCELADO, "", &envelope)
+        }
+        LifecycleEvent::Extended(auction) => {
+            let envelope = Envelope::new(
+                MessageType::AuctionExtended,
+                AuctionExtended { auction_id: auction.id, end_timestamp: auction.end_timestamp }
+            ).with_message_id(format!("auction-{}-extended-{}", auction.id, auction.end_timestamp));
+            publisher.publish_envelope(LEILAO_PRORROGADO, "", &envelope)
+        }
+    }
+}
+
+/*============================================= PUBLISH - END ============================================= */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    use crate::storage::MemoryAuctionStore;
+
+    #[tokio::test]
+    async fn fires_due_auctions_in_order_and_skips_moved_ones() {
+        let (lifecycle_tx, mut lifecycle_rx) = mpsc::channel(10);
+        let mut schedule = Schedule::default();
+        schedule.insert(Auction::new(1, "lamp".to_string(), 100, 200), AuctionState::Scheduled);
+        schedule.insert(Auction::new(2, "chair".to_string(), 50, 150), AuctionState::Scheduled);
+        assert_eq!(schedule.next_due(), Some(50));
+
+        // moved to a later start, the entry at 100 is stale
+        schedule.auction_mut(1).start_timestamp = 300;
+        schedule.auction_mut(1).end_timestamp = 400;
+        schedule.wake_at(1);
+
+        schedule.fire_due(160, &lifecycle_tx).await;
+        assert!(matches!(lifecycle_rx.try_recv(), Ok(LifecycleEvent::Started(a)) if a.id == 2));
+        assert!(matches!(lifecycle_rx.try_recv(), Ok(LifecycleEvent::Finished(a)) if a.id == 2));
+        assert!(lifecycle_rx.try_recv().is_err());
+        assert_eq!(schedule.state_of(1), Some(AuctionState::Scheduled));
+        assert_eq!(schedule.next_due(), Some(300));
+    }
+
+    #[tokio::test]
+    async fn extend_never_moves_a_running_auction_end_earlier() {
+        let (lifecycle_tx, mut lifecycle_rx) = mpsc::channel(10);
+        let mut store = MemoryAuctionStore::default();
+        let mut ids = IdAllocator::open(None, 0, 1, &[]).unwrap();
+        let mut schedule = Schedule::default();
+        schedule.insert(Auction::new(1, "lamp".to_string(), 100, 500), AuctionState::Started);
+
+        let earlier = ScheduleCommand::Extend { auction_id: 1, end_timestamp: 300 };
+        let result = apply_command(earlier, &mut schedule, &mut store, &mut ids, &lifecycle_tx, 200).await;
+        assert!(result.is_err());
+        assert_eq!(schedule.auction_mut(1).end_timestamp, 500);
+        assert!(lifecycle_rx.try_recv().is_err());
+
+        let later = ScheduleCommand::Extend { auction_id: 1, end_timestamp: 800 };
+        let result = apply_command(later, &mut schedule, &mut store, &mut ids, &lifecycle_tx, 200).await;
+        assert!(result.is_ok());
+        assert!(matches!(lifecycle_rx.try_recv(), Ok(LifecycleEvent::Extended(a)) if a.end_timestamp == 800));
+    }
+
+    #[tokio::test]
+    async fn view_follows_changes_and_finished_auctions_are_forgotten() {
+        let (lifecycle_tx, _lifecycle_rx) = mpsc::channel(10);
+        let mut schedule = Schedule::default();
+        let mut view = BTreeMap::new();
+        schedule.insert(Auction::new(1, "lamp".to_string(), 100, 200), AuctionState::Scheduled);
+        schedule.insert(Auction::new(2, "chair".to_string(), 100, 900), AuctionState::Scheduled);
+        assert!(schedule.update_view(&mut view));
+        assert!(!schedule.update_view(&mut view));
+
+        schedule.fire_due(250, &lifecycle_tx).await;
+        assert!(schedule.update_view(&mut view));
+        assert_eq!(view[&1].status, AuctionStatus::Closed);
+        assert_eq!(view[&2].status, AuctionStatus::Open);
+        let reply = answer_query(&view, Query::GetAuction { auction_id: 1 });
+        assert!(matches!(reply, QueryReply::Auction(Some(a)) if a.auction.id == 1));
+
+        assert_eq!(schedule.next_due(), Some(900));
+        schedule.fire_due(250 + FINISHED_RETENTION_MS, &lifecycle_tx).await;
+        assert!(schedule.update_view(&mut view));
+        assert_eq!(schedule.state_of(1), None);
+        assert_eq!(view.keys().copied().collect::<Vec<_>>(), vec![2]);
+        assert!(matches!(answer_query(&view, Query::GetAuction { auction_id: 1 }), QueryReply::Auction(None)));
+    }
+
+    #[tokio::test]
+    async fn reschedule_refuses_a_start_that_already_passed() {
+        let (lifecycle_tx, _lifecycle_rx) = mpsc::channel(10);
+        let mut store = MemoryAuctionStore::default();
+        let mut ids = IdAllocator::open(None, 0, 1, &[]).unwrap();
+        let mut schedule = Schedule::default();
+        schedule.insert(Auction::new(1, "lamp".to_string(), 10_000, 20_000), AuctionState::Scheduled);
+
+        let past = ScheduleCommand::Reschedule { auction_id: 1, start_timestamp: 3_000, end_timestamp: 30_000 };
+        let result = apply_command(past, &mut schedule, &mut store, &mut ids, &lifecycle_tx, 5_000).await;
+        assert_eq!(result, Err("The start cannot be in the past".to_string()));
+        assert_eq!(schedule.auction_mut(1).start_timestamp, 10_000);
+
+        let now = ScheduleCommand::Reschedule { auction_id: 1, start_timestamp: 4_900, end_timestamp: 30_000 };
+        let result = apply_command(now, &mut schedule, &mut store, &mut ids, &lifecycle_tx, 5_000).await;
+        assert!(result.is_ok());
+        assert_eq!(schedule.next_due(), Some(4_900));
+    }
+}
@@ -0,0 +1,238 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+
+/// How far ahead an auction can end. Anything later is most likely a typo, and
+/// far enough out it is a time the clients cannot even display.
+const MAX_AHEAD_MS: u64 = 10 * 366 * 86_400_000;
+
+/// Splits a command line on whitespace, keeping `"quoted words"` together.
+pub fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut quote: Option<char> = None;
+
+    for c in line.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                in_token = true;
+            }
+            None if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut current));
+                    in_token = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_token = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err("Unterminated quote".to_string());
+    }
+    if in_token {
+        tokens.push(current);
+    }
+
+    Ok(tokens)
+}
+
+/// Parses `<start> <end>` or `<start> for <duration>` and checks that the auction
+/// starts now or later and ends after it starts, within the next ten years.
+///
+/// Times can be `now`, relative (`+5m`, `in 2h`), local (`14:30`, `2025-06-01 14:30`,
+/// `2025-06-01T14:30:00`) or epoch milliseconds.
+pub fn parse_schedule(tokens: &[String], now: DateTime<Local>) -> Result<(u64, u64), String> {
+    let (start, used) = parse_instant(tokens, now)?;
+    let rest = &tokens[used..];
+
+    let end = match rest {
+        [keyword, duration] if keyword.eq_ignore_ascii_case("for") => add_duration(start, duration)?,
+        [] => return Err("Missing end time, use '<end>' or 'for <duration>'".to_string()),
+        _ => {
+            let (end, used) = parse_instant(rest, now)?;
+            if used != rest.len() {
+                return Err(format!("Unexpected '{}' after the end time", rest[used..].join(" ")));
+            }
+            end
+        }
+    };
+
+    let now = now.timestamp_millis() as u64;
+    if start < now {
+        return Err("The start cannot be in the past".to_string());
+    }
+    if end <= start {
+        return Err("The end must be after the start".to_string());
+    }
+    check_horizon(end, now)?;
+
+    Ok((start, end))
+}
+
+/// Parses a single time, which has to be in the future, within the next ten years.
+pub fn parse_end(tokens: &[String], now: DateTime<Local>) -> Result<u64, String> {
+    let (end, used) = parse_instant(tokens, now)?;
+    if used != tokens.len() {
+        return Err(format!("Unexpected '{}' after the end time", tokens[used..].join(" ")));
+    }
+    let now = now.timestamp_millis() as u64;
+    if end <= now {
+        return Err("The end must be in the future".to_string());
+    }
+    check_horizon(end, now)?;
+
+    Ok(end)
+}
+
+fn check_horizon(end: u64, now: u64) -> Result<(), String> {
+    if end - now > MAX_AHEAD_MS {
+        return Err("The end cannot be more than ten years from now".to_string());
+    }
+    Ok(())
+}
+
+/// Returns the time in epoch milliseconds and how many tokens it took.
+fn parse_instant(tokens: &[String], now: DateTime<Local>) -> Result<(u64, usize), String> {
+    let first = tokens.first().ok_or("Missing time")?.to_ascii_lowercase();
+    let now_ms = now.timestamp_millis() as u64;
+
+    if first == "now" {
+        return Ok((now_ms, 1));
+    }
+    if first == "in" {
+        let duration = tokens.get(1).ok_or("Missing duration after 'in'")?;
+        return Ok((add_duration(now_ms, duration)?, 2));
+    }
+    if let Some(duration) = first.strip_prefix('+') {
+        return Ok((add_duration(now_ms, duration)?, 1));
+    }
+    // raw epoch milliseconds, as the command used to take
+    if first.len() >= 10 && first.chars().all(|c| c.is_ascii_digit()) {
+        return first.parse().map(|ms| (ms, 1)).map_err(|_| format!("Invalid timestamp '{first}'"));
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(&first, "%Y-%m-%d") {
+        let time = tokens.get(1).ok_or_else(|| format!("Missing time of day after '{first}'"))?;
+        return Ok((local_millis(date.and_time(parse_time_of_day(time)?))?, 2));
+    }
+    for format in ["%Y-%m-%dt%H:%M:%S", "%Y-%m-%dt%H:%M"] {
+        if let Ok(date_time) = NaiveDateTime::parse_from_str(&first, format) {
+            return Ok((local_millis(date_time)?, 1));
+        }
+    }
+    if let Ok(time) = parse_time_of_day(&first) {
+        return Ok((local_millis(now.date_naive().and_time(time))?, 1));
+    }
+
+    Err(format!("Invalid time '{}', use now, +5m, in 2h, 14:30 or 2025-06-01 14:30", tokens[0]))
+}
+
+/// `time` plus the duration, refusing durations that would not fit a timestamp.
+fn add_duration(time: u64, duration: &str) -> Result<u64, String> {
+    time.checked_add(parse_duration(duration)?)
+        .ok_or_else(|| invalid_duration(duration))
+}
+
+/// Durations like `90s`, `30m`, `2h`, `1d` or `1h30m`, in milliseconds.
+fn parse_duration(duration: &str) -> Result<u64, String> {
+    let invalid = || invalid_duration(duration);
+
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in duration.to_ascii_lowercase().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit_ms = match c {
+            's' => 1_000,
+            'm' => 60_000,
+            'h' => 3_600_000,
+            'd' => 86_400_000,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        total = value
+            .checked_mul(unit_ms)
+            .and_then(|ms| total.checked_add(ms))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+
+    if !number.is_empty() || total == 0 {
+        return Err(invalid());
+    }
+
+    Ok(total)
+}
+
+fn invalid_duration(duration: &str) -> String {
+    format!("Invalid duration '{duration}', use e.g. 30s, 5m, 2h, 1d or 1h30m")
+}
+
+fn parse_time_of_day(time: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+        .map_err(|_| format!("Invalid time of day '{time}'"))
+}
+
+fn local_millis(date_time: NaiveDateTime) -> Result<u64, String> {
+    Local
+        .from_local_datetime(&date_time)
+        .earliest()
+        .map(|time| time.timestamp_millis() as u64)
+        .ok_or_else(|| format!("{date_time} does not exist in the local time zone"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2030, 5, 10, 12, 0, 0).unwrap()
+    }
+
+    fn tokens(line: &str) -> Vec<String> {
+        tokenize(line).unwrap()
+    }
+
+    #[test]
+    fn tokenize_keeps_quoted_items_together() {
+        assert_eq!(tokens(r#"create "Old Lamp" +5m for 1h"#), ["create", "Old Lamp", "+5m", "for", "1h"]);
+        assert!(tokenize(r#"create "Old Lamp +5m"#).is_err());
+    }
+
+    #[test]
+    fn parses_relative_times_and_durations() {
+        let now_ms = now().timestamp_millis() as u64;
+
+        assert_eq!(parse_schedule(&tokens("+5m for 30m"), now()), Ok((now_ms + 300_000, now_ms + 2_100_000)));
+        assert_eq!(parse_schedule(&tokens("now in 1h30m"), now()), Ok((now_ms, now_ms + 5_400_000)));
+        assert_eq!(parse_schedule(&tokens("13:00 2030-05-10 14:00"), now()), Ok((now_ms + 3_600_000, now_ms + 7_200_000)));
+    }
+
+    #[test]
+    fn rejects_past_starts_and_ends_before_the_start() {
+        assert_eq!(parse_schedule(&tokens("11:00 for 1h"), now()), Err("The start cannot be in the past".to_string()));
+        assert_eq!(parse_schedule(&tokens("+1h +30m"), now()), Err("The end must be after the start".to_string()));
+        assert!(parse_schedule(&tokens("+1h for 5x"), now()).is_err());
+        assert!(parse_schedule(&tokens("+99999999999999999d for 1h"), now()).is_err());
+        assert!(parse_schedule(&tokens("now for 213503982334d"), now()).is_err());
+    }
+
+    #[test]
+    fn rejects_ends_past_the_horizon() {
+        let too_far = "The end cannot be more than ten years from now".to_string();
+        assert_eq!(parse_schedule(&tokens("now for 999999999d"), now()), Err(too_far.clone()));
+        assert_eq!(parse_schedule(&tokens("now 99999999999999999"), now()), Err(too_far.clone()));
+        assert_eq!(parse_end(&tokens("in 4000d"), now()), Err(too_far));
+        assert!(parse_end(&tokens("in 3000d"), now()).is_ok());
+    }
+}
@@ -1,6 +0,0 @@
-
-const LEILAO_FINALIZADO: &str = "leilao_finalizado";
-const LEILAO_VENCEDOR: &str = "leilao_vencedor";
-const LEILAO_INICIADO: &str = "leilao_iniciado";
-const LANCE_REALIZADO: &str = "lance_realizado";
-
@@ -0,0 +1,291 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use shared::models::{KeyOperation, KeyRejection, KeyRequest, KeySignature, RejectionReason};
+use shared::signing::{self, VerifyingKey};
+
+use crate::replay::{ReplayGuard, REPLAY_WINDOW_MS};
+
+/// The key of a client as the registry stores it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyRecord {
+    /// OpenSSH line or PEM, as the client sent it.
+    pub public_key: String,
+    #[serde(default)]
+    pub revoked: bool,
+    pub updated_at: u128,
+}
+
+/// The public keys bids are checked with, by client id.
+///
+/// Kept in one JSON file that is replaced on every change. The file is read again
+/// when someone else changes it, so keys edited by hand apply without a restart.
+pub struct KeyRegistry {
+    path: Option<PathBuf>,
+    /// Modification time of the file as last read or written.
+    loaded: Option<SystemTime>,
+    records: BTreeMap<u32, KeyRecord>,
+    replay_guard: ReplayGuard,
+}
+
+impl KeyRegistry {
+    /// Opens the registry selected by `BID_SRV_KEYS` (defaults to `bid-srv/data/keys.json`,
+    /// `memory` keeps it in RAM) and takes in the `client_<id>` files of `bid-srv/keys`
+    /// for clients it does not know yet.
+    pub fn open_default() -> io::Result<Self> {
+        let location = std::env::var("BID_SRV_KEYS")
+            .unwrap_or_else(|_| "bid-srv/data/keys.json".to_string());
+
+        let mut registry = Self::open((location != "memory").then(|| PathBuf::from(location)))?;
+        let imported = registry.import_dir("bid-srv/keys", now_millis())?;
+        if imported > 0 {
+            println!("Imported {imported} client keys from bid-srv/keys");
+        }
+        Ok(registry)
+    }
+
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut registry = KeyRegistry {
+            path,
+            loaded: None,
+            records: BTreeMap::new(),
+            replay_guard: ReplayGuard::new(REPLAY_WINDOW_MS),
+        };
+        registry.load()?;
+        Ok(registry)
+    }
+
+    /// Registers the keys in `client_<id>` files for the clients without a record.
+    pub fn import_dir<P: AsRef<Path>>(&mut self, folder: P, now: u128) -> io::Result<usize> {
+        let entries = match fs::read_dir(folder) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        let mut imported = 0;
+        for entry in entries {
+            let path = entry?.path();
+
+            if let Some(filename) = path.file_name().and_then(|n| n.to_str())
+                && let Some(id_str) = filename.strip_prefix("client_")
+                && let Ok(id) = id_str.parse::<u32>()
+                && !self.records.contains_key(&id) {
+                let public_key = fs::read_to_string(&path)?;
+                match VerifyingKey::parse(&public_key) {
+                    Ok(_) => {
+                        self.records.insert(id, KeyRecord { public_key, revoked: false, updated_at: now });
+                        imported += 1;
+                    }
+                    Err(e) => println!("Ignoring the key of client {id}: {e}"),
+                }
+            }
+        }
+
+        if imported > 0 {
+            self.save()?;
+        }
+        Ok(imported)
+    }
+
+    /// The key to check the bids of `client_id` with.
+    pub fn lookup(&mut self, client_id: u32) -> Result<VerifyingKey, RejectionReason> {
+        self.reload_if_changed();
+
+        let record = self.records.get(&client_id).ok_or(RejectionReason::UnknownClientKey)?;
+        if record.revoked {
+            return Err(RejectionReason::RevokedClientKey);
+        }
+        VerifyingKey::parse(&record.public_key).map_err(|e| {
+            println!("Stored key of client {client_id} is unusable: {e}");
+            RejectionReason::UnknownClientKey
+        })
+    }
+
+    /// Checks the signatures of the request and applies it. Nothing changes unless it
+    /// reached the disk.
+    pub fn apply(&mut self, request: &KeyRequest, now: u128) -> Result<(), KeyRejection> {
+        self.reload_if_changed();
+        let client_id = request.client_id;
+
+        match (&request.operation, self.records.get(&client_id)) {
+            (KeyOperation::Register { .. }, Some(_)) => return Err(KeyRejection::AlreadyRegistered),
+            (KeyOperation::Register { .. }, None) => {}
+            (_, None) => return Err(KeyRejection::UnknownClient),
+            (_, Some(record)) if record.revoked => return Err(KeyRejection::Revoked),
+            (_, Some(record)) => {
+                let current = VerifyingKey::parse(&record.public_key)
+                    .map_err(|e| KeyRejection::Storage(format!("stored key is unusable: {e}")))?;
+                if !is_signed_by(request, request.signature.as_ref(), &current) {
+                    return Err(KeyRejection::BadSignature);
+                }
+            }
+        }
+
+        if let Some(public_key) = request.new_key() {
+            let new_key = VerifyingKey::parse(public_key).map_err(|e| KeyRejection::InvalidKey(e.to_string()))?;
+            if !is_signed_by(request, request.proof.as_ref(), &new_key) {
+                return Err(KeyRejection::BadProof);
+            }
+        }
+
+        // only signed requests that reach the disk use up a nonce, same as bids
+        self.replay_guard
+            .check_nonce(client_id, request.nonce, request.timestamp, now)
+            .map_err(|reason| match reason {
+                RejectionReason::StaleBid => KeyRejection::StaleRequest,
+                _ => KeyRejection::ReplayedRequest,
+            })?;
+
+        let record = match &request.operation {
+            KeyOperation::Register { public_key } | KeyOperation::Rotate { public_key } => {
+                KeyRecord { public_key: public_key.clone(), revoked: false, updated_at: now }
+            }
+            KeyOperation::Revoke => KeyRecord { revoked: true, updated_at: now, ..self.records[&client_id].clone() },
+        };
+
+        let previous = self.records.insert(client_id, record);
+        if let Err(e) = self.save() {
+            match previous {
+                Some(previous) => self.records.insert(client_id, previous),
+                None => self.records.remove(&client_id),
+            };
+            return Err(KeyRejection::Storage(e.to_string()));
+        }
+        self.replay_guard.record_nonce(client_id, request.nonce, request.timestamp);
+        Ok(())
+    }
+
+    /// Keeps the keys in memory if the file turns out to be unreadable.
+    fn reload_if_changed(&mut self) {
+        let Some(path) = self.path.clone() else { return };
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        if modified == self.loaded {
+            return;
+        }
+
+        match self.load() {
+            Ok(()) => println!("Reloaded {} client keys from {}", self.records.len(), path.display()),
+            Err(e) => println!("Failed to reload client keys, keeping the previous ones: {e}"),
+        }
+    }
+
+    fn load(&mut self) -> io::Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.loaded = None;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        self.records = serde_json::from_str(&content)?;
+        self.loaded = fs::metadata(path)?.modified().ok();
+        Ok(())
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec_pretty(&self.records)?)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        self.loaded = fs::metadata(path)?.modified().ok();
+        Ok(())
+    }
+}
+
+fn is_signed_by(request: &KeyRequest, signature: Option<&KeySignature>, key: &VerifyingKey) -> bool {
+    let Some(signature) = signature else { return false };
+
+    match signing::verify_key_request(request, signature, key) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Key request signature check failed: {e}");
+            false
+        }
+    }
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::signing::{SignatureScheme, SigningKey};
+
+    const SCHEME: SignatureScheme = SignatureScheme::RsaPkcs1v15Sha256;
+
+    fn key(pem: &str) -> (SigningKey, String) {
+        let key = SigningKey::from_pem(pem).unwrap();
+        let public_key = key.verifying_key().to_pem().unwrap();
+        (key, public_key)
+    }
+
+    fn request(
+        operation: KeyOperation,
+        nonce: u64,
+        signed_by: Option<&SigningKey>,
+        proven_by: Option<&SigningKey>
+    ) -> KeyRequest {
+        let mut request = KeyRequest { nonce, timestamp: 1_000, ..KeyRequest::new(7, operation) };
+        request.signature = signed_by.map(|key| signing::sign_key_request(&request, SCHEME, key).unwrap());
+        request.proof = proven_by.map(|key| signing::sign_key_request(&request, SCHEME, key).unwrap());
+        request
+    }
+
+    #[test]
+    fn registers_rotates_and_revokes_keys() {
+        let path = std::env::temp_dir().join(format!("bid-srv-keys-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let (first, first_public) = key(include_str!("../../auction-client/keys/client_0"));
+        let (second, second_public) = key(include_str!("../../auction-client/keys/client_1"));
+
+        let mut registry = KeyRegistry::open(Some(path.clone())).unwrap();
+        assert_eq!(registry.lookup(7), Err(RejectionReason::UnknownClientKey));
+
+        let register = KeyOperation::Register { public_key: first_public.clone() };
+        assert_eq!(registry.apply(&request(register.clone(), 1, None, None), 1_000), Err(KeyRejection::BadProof));
+        assert_eq!(registry.apply(&request(register.clone(), 2, None, Some(&first)), 1_000), Ok(()));
+        assert_eq!(
+            registry.apply(&request(register, 3, None, Some(&first)), 1_000),
+            Err(KeyRejection::AlreadyRegistered)
+        );
+
+        let rotate = KeyOperation::Rotate { public_key: second_public.clone() };
+        assert_eq!(
+            registry.apply(&request(rotate.clone(), 4, Some(&second), Some(&second)), 1_000),
+            Err(KeyRejection::BadSignature)
+        );
+        assert_eq!(registry.apply(&request(rotate.clone(), 5, Some(&first), Some(&second)), 1_000), Ok(()));
+        assert_eq!(registry.lookup(7), Ok(VerifyingKey::parse(&second_public).unwrap()));
+
+        // another bid-srv sharing the file gets the same keys
+        let mut other = KeyRegistry::open(Some(path.clone())).unwrap();
+        assert_eq!(other.lookup(7), Ok(VerifyingKey::parse(&second_public).unwrap()));
+        assert_eq!(registry.apply(&request(KeyOperation::Revoke, 6, Some(&second), None), 1_000), Ok(()));
+        assert_eq!(registry.lookup(7), Err(RejectionReason::RevokedClientKey));
+        assert_eq!(
+            registry.apply(&request(rotate, 7, Some(&second), Some(&second)), 1_000),
+            Err(KeyRejection::Revoked)
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use shared::models::{Auction, Bid};
+
+/// Every state change of bid-srv, in the order it happened.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum LedgerEntry {
+    AuctionStarted(Auction),
+    BidAccepted(Bid),
+    AuctionFinished(u32),
+    AuctionCancelled(u32),
+    AuctionExtended { auction_id: u32, end_timestamp: u128 },
+}
+
+impl LedgerEntry {
+    fn auction_id(&self) -> u32 {
+        match self {
+            LedgerEntry::AuctionStarted(auction) => auction.id,
+            LedgerEntry::BidAccepted(bid) => bid.auction_id,
+            LedgerEntry::AuctionFinished(auction_id)
+            | LedgerEntry::AuctionCancelled(auction_id)
+            | LedgerEntry::AuctionExtended { auction_id, .. } => *auction_id,
+        }
+    }
+}
+
+/// How long after its end a finished or cancelled auction is still replayed,
+/// for bid history queries and the replay guard.
+const FINISHED_RETENTION_MS: u128 = 60 * 60 * 1000;
+
+/// Append-only, fsync'd log of `LedgerEntry`s, one JSON document per line.
+///
+/// Auctions that ended long ago are moved to an archive next to it on replay,
+/// so startup only reads what is still in play.
+pub struct BidLedger {
+    path: PathBuf,
+    file: File,
+}
+
+impl BidLedger {
+    /// Opens the ledger selected by `BID_SRV_LEDGER` (defaults to `bid-srv/data/bids.jsonl`).
+    pub fn open_default() -> io::Result<Self> {
+        let path = std::env::var("BID_SRV_LEDGER")
+            .unwrap_or_else(|_| "bid-srv/data/bids.jsonl".to_string());
+        Self::open(path)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+
+        Ok(BidLedger { path, file })
+    }
+
+    /// Where the entries of auctions that ended long ago are kept.
+    pub fn archive_path(&self) -> PathBuf {
+        self.path.with_extension("archive.jsonl")
+    }
+
+    /// Writes the entry and waits for it to reach the disk.
+    pub fn append(&mut self, entry: &LedgerEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+
+    /// Rebuilds the auctions and accepted bids from the entries written so far, after
+    /// archiving the auctions that finished or were cancelled more than
+    /// `FINISHED_RETENTION_MS` before `now`.
+    pub fn replay(&mut self, now: u128) -> io::Result<(Vec<Auction>, Vec<Bid>)> {
+        let entries = self.read_entries()?;
+
+        let ended = ended_before(&entries, now.saturating_sub(FINISHED_RETENTION_MS));
+        let (archived, entries): (Vec<LedgerEntry>, Vec<LedgerEntry>) = entries
+            .into_iter()
+            .partition(|entry| ended.contains(&entry.auction_id()));
+        if !archived.is_empty() {
+            self.archive(&archived, &entries)?;
+            println!("Archived {} ledger entries of {} finished auctions", archived.len(), ended.len());
+        }
+
+        let mut auctions: Vec<Auction> = Vec::new();
+        let mut bids: Vec<Bid> = Vec::new();
+        for entry in entries {
+            match entry {
+                LedgerEntry::AuctionStarted(auction) => {
+                    auctions.retain(|a| a.id != auction.id);
+                    auctions.push(auction);
+                }
+                LedgerEntry::BidAccepted(bid) => bids.push(bid),
+                LedgerEntry::AuctionFinished(auction_id) | LedgerEntry::AuctionCancelled(auction_id) => {
+                    if let Some(auction) = auctions.iter_mut().find(|a| a.id == auction_id) {
+                        auction.status = false;
+                    }
+                }
+                LedgerEntry::AuctionExtended { auction_id, end_timestamp } => {
+                    if let Some(auction) = auctions.iter_mut().find(|a| a.id == auction_id) {
+                        auction.end_timestamp = end_timestamp;
+                    }
+                }
+            }
+        }
+
+        Ok((auctions, bids))
+    }
+
+    /// Appends `archived` to the archive, then swaps the ledger for one with only `kept`.
+    /// A crash in between leaves the entries in both, and they are archived again next time.
+    fn archive(&mut self, archived: &[LedgerEntry], kept: &[LedgerEntry]) -> io::Result<()> {
+        let mut archive = OpenOptions::new().create(true).append(true).open(self.archive_path())?;
+        write_entries(&mut archive, archived)?;
+        archive.sync_all()?;
+
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        write_entries(&mut tmp, kept)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        sync_parent_dir(&self.path)?;
+
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    fn read_entries(&self) -> io::Result<Vec<LedgerEntry>> {
+        let mut entries = Vec::new();
+
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut line = String::new();
+        let mut line_number = 0;
+        let mut offset = 0;
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            line_number += 1;
+            let line_start = offset;
+            offset += read as u64;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let entry: LedgerEntry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                // a crash mid-append only tears the last line, which was never acted on;
+                // cut it off so the next entry starts on a line of its own
+                Err(e) if reader.fill_buf()?.is_empty() => {
+                    println!("Dropping torn ledger entry at line {line_number}: {e}");
+                    self.file.set_len(line_start)?;
+                    break;
+                }
+                Err(e) => return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt ledger entry at line {line_number}: {e}")
+                )),
+            };
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Auctions that finished or were cancelled, and whose end is before `cutoff`.
+fn ended_before(entries: &[LedgerEntry], cutoff: u128) -> HashSet<u32> {
+    let mut end_timestamps: HashMap<u32, u128> = HashMap::new();
+    let mut closed: HashSet<u32> = HashSet::new();
+    for entry in entries {
+        match entry {
+            LedgerEntry::AuctionStarted(auction) => {
+                end_timestamps.insert(auction.id, auction.end_timestamp);
+            }
+            LedgerEntry::AuctionExtended { auction_id, end_timestamp } => {
+                end_timestamps.insert(*auction_id, *end_timestamp);
+            }
+            LedgerEntry::AuctionFinished(auction_id) | LedgerEntry::AuctionCancelled(auction_id) => {
+                closed.insert(*auction_id);
+            }
+            LedgerEntry::BidAccepted(_) => {}
+        }
+    }
+
+    closed
+        .into_iter()
+        .filter(|auction_id| end_timestamps.get(auction_id).is_some_and(|end| *end < cutoff))
+        .collect()
+}
+
+fn write_entries(file: &mut File, entries: &[LedgerEntry]) -> io::Result<()> {
+    for entry in entries {
+        serde_json::to_writer(&mut *file, entry)?;
+        file.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Flushes the directory entry of `path`, so a rename survives a crash.
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_rebuilds_auctions_and_bids() {
+        let path = std::env::temp_dir().join(format!("bid-ledger-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut ledger = BidLedger::open(&path).unwrap();
+        ledger.append(&LedgerEntry::AuctionStarted(Auction::new(3, "lamp".to_string(), 0, 10))).unwrap();
+        ledger.append(&LedgerEntry::BidAccepted(Bid {
+            auction_id: 3,
+            client_id: 1,
+            value: "12.50".parse().unwrap(),
+            signature: String::new(),
+            public_key: String::new(),
+            valid: true,
+            quantity: 1,
+            nonce: 0,
+            timestamp: 0,
+            signature_scheme: Default::default(),
+        })).unwrap();
+        ledger.append(&LedgerEntry::AuctionFinished(3)).unwrap();
+        drop(ledger);
+
+        let (auctions, bids) = BidLedger::open(&path).unwrap().replay(0).unwrap();
+        assert_eq!(auctions.len(), 1);
+        assert!(!auctions[0].status);
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].value.to_string(), "12.50 BRL");
+
+        // a torn last entry is cut off and the ledger keeps going after it
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"AuctionFini").unwrap();
+        let mut ledger = BidLedger::open(&path).unwrap();
+        assert_eq!(ledger.replay(0).unwrap().1.len(), 1);
+        ledger.append(&LedgerEntry::AuctionCancelled(3)).unwrap();
+        assert_eq!(BidLedger::open(&path).unwrap().replay(0).unwrap().1.len(), 1);
+
+        // a corrupt entry before the end is not silently skipped
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, format!("{{\"AuctionFini\n{content}")).unwrap();
+        assert!(BidLedger::open(&path).unwrap().replay(0).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_archives_auctions_that_ended_long_ago() {
+        let path = std::env::temp_dir().join(format!("bid-ledger-archive-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let bid = |auction_id| LedgerEntry::BidAccepted(Bid {
+            auction_id,
+            client_id: 1,
+            value: "12.50".parse().unwrap(),
+            signature: String::new(),
+            public_key: String::new(),
+            valid: true,
+            quantity: 1,
+            nonce: auction_id as u64,
+            timestamp: 0,
+            signature_scheme: Default::default(),
+        });
+
+        let mut ledger = BidLedger::open(&path).unwrap();
+        let _ = fs::remove_file(ledger.archive_path());
+        ledger.append(&LedgerEntry::AuctionStarted(Auction::new(1, "lamp".to_string(), 0, 1_000))).unwrap();
+        ledger.append(&LedgerEntry::AuctionStarted(Auction::new(2, "chair".to_string(), 0, 1_000))).unwrap();
+        ledger.append(&bid(1)).unwrap();
+        ledger.append(&bid(2)).unwrap();
+        ledger.append(&LedgerEntry::AuctionFinished(1)).unwrap();
+        // extended past the cutoff before it was closed, so it stays
+        ledger.append(&LedgerEntry::AuctionExtended { auction_id: 2, end_timestamp: 5_000 }).unwrap();
+        ledger.append(&LedgerEntry::AuctionCancelled(2)).unwrap();
+
+        let now = 2_000 + FINISHED_RETENTION_MS;
+        let (auctions, bids) = ledger.replay(now).unwrap();
+        assert_eq!(auctions.iter().map(|a| a.id).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(bids.iter().map(|b| b.auction_id).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(fs::read_to_string(ledger.archive_path()).unwrap().lines().count(), 3);
+
+        // the ledger itself was rewritten and still takes appends
+        ledger.append(&bid(2)).unwrap();
+        let mut ledger = BidLedger::open(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 5);
+        assert_eq!(ledger.replay(now).unwrap().1.len(), 2);
+
+        fs::remove_file(ledger.archive_path()).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use shared::models::{Bid, RejectionReason};
+
+/// How far the timestamp of a bid may be from the clock of bid-srv, either way.
+pub const REPLAY_WINDOW_MS: u128 = 60_000;
+
+/// Refuses bids submitted twice, such as a signed bid copied off `lance_realizado`.
+///
+/// Bids signed more than `window` away from now are stale, so a nonce only has to
+/// be remembered for as long as its bid could still be taken.
+pub struct ReplayGuard {
+    window: u128,
+    /// Timestamp of every recent bid, by client and nonce.
+    seen: HashMap<u32, HashMap<u64, u128>>,
+}
+
+impl ReplayGuard {
+    pub fn new(window: u128) -> Self {
+        ReplayGuard { window, seen: HashMap::new() }
+    }
+
+    /// Remembers bids taken before a restart, as replayed from the ledger.
+    pub fn remember<'a>(&mut self, bids: impl IntoIterator<Item = &'a Bid>, now: u128) {
+        for bid in bids {
+            if self.is_fresh(bid.timestamp, now) {
+                self.record(bid);
+            }
+        }
+    }
+
+    /// Says why the nonce of a bid with a valid signature cannot be used, if it cannot.
+    /// The nonce stays free until the bid is recorded.
+    pub fn check(&mut self, bid: &Bid, now: u128) -> Result<(), RejectionReason> {
+        self.check_nonce(bid.client_id, bid.nonce, bid.timestamp, now)
+    }
+
+    /// Uses up the nonce of a bid that passed `check`.
+    pub fn record(&mut self, bid: &Bid) {
+        self.record_nonce(bid.client_id, bid.nonce, bid.timestamp);
+    }
+
+    /// Same as `check`, for anything else a client signs with a nonce and a timestamp.
+    pub fn check_nonce(&mut self, client_id: u32, nonce: u64, timestamp: u128, now: u128) -> Result<(), RejectionReason> {
+        let window = self.window;
+        self.seen.retain(|_, nonces| {
+            nonces.retain(|_, timestamp| *timestamp + window >= now);
+            !nonces.is_empty()
+        });
+
+        if !self.is_fresh(timestamp, now) {
+            return Err(RejectionReason::StaleBid);
+        }
+
+        if self.seen.get(&client_id).is_some_and(|nonces| nonces.contains_key(&nonce)) {
+            return Err(RejectionReason::ReplayedBid);
+        }
+        Ok(())
+    }
+
+    /// Same as `record`, for anything else a client signs with a nonce and a timestamp.
+    pub fn record_nonce(&mut self, client_id: u32, nonce: u64, timestamp: u128) {
+        self.seen.entry(client_id).or_default().insert(nonce, timestamp);
+    }
+
+    fn is_fresh(&self, timestamp: u128, now: u128) -> bool {
+        timestamp.abs_diff(now) <= self.window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bid(client_id: u32, nonce: u64, timestamp: u128) -> Bid {
+        Bid {
+            auction_id: 1,
+            client_id,
+            value: "10".parse().unwrap(),
+            signature: String::new(),
+            public_key: String::new(),
+            valid: false,
+            quantity: 1,
+            nonce,
+            timestamp,
+            signature_scheme: Default::default(),
+        }
+    }
+
+    #[test]
+    fn refuses_stale_and_repeated_nonces() {
+        let mut guard = ReplayGuard::new(1_000);
+        guard.remember(&[bid(1, 7, 10_000)], 10_500);
+
+        assert_eq!(guard.check(&bid(1, 7, 10_000), 10_600), Err(RejectionReason::ReplayedBid));
+        assert_eq!(guard.check(&bid(2, 7, 10_000), 10_600), Ok(()));
+        assert_eq!(guard.check(&bid(1, 8, 8_000), 10_600), Err(RejectionReason::StaleBid));
+        assert_eq!(guard.check(&bid(1, 9, 12_000), 10_600), Err(RejectionReason::StaleBid));
+        // once the first bid is stale its nonce can be forgotten
+        assert_eq!(guard.check(&bid(1, 7, 11_500), 11_600), Ok(()));
+    }
+
+    #[test]
+    fn nonce_is_only_used_up_once_recorded() {
+        let mut guard = ReplayGuard::new(1_000);
+
+        // the bid could not be written, so its redelivery must still go through
+        assert_eq!(guard.check(&bid(1, 7, 10_000), 10_100), Ok(()));
+        assert_eq!(guard.check(&bid(1, 7, 10_000), 10_200), Ok(()));
+        guard.record(&bid(1, 7, 10_000));
+        assert_eq!(guard.check(&bid(1, 7, 10_000), 10_300), Err(RejectionReason::ReplayedBid));
+    }
+}
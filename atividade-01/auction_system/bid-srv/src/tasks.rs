@@ -1,115 +1,167 @@
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use lapin::{
-    options::{
-        BasicConsumeOptions, 
-        BasicPublishOptions, 
-    }, 
-    types::FieldTable, Channel, Connection
-};
-use futures_lite::stream::StreamExt;
-use rsa::{
-    pkcs8::DecodePublicKey, 
-    RsaPublicKey, 
-    Pkcs1v15Sign
-};
-use sha2::{Digest, Sha256};
-use base64::engine::general_purpose;
-use base64::Engine;
-use std::{fs, path::Path};
-use serde_json;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use shared::broker::{Broker, Publisher};
 use shared::models::{
     Auction,
-    Bid
+    AuctionCancelled,
+    AuctionExtended,
+    AuctionFinished,
+    AuctionKind,
+    AuctionOutcome,
+    Bid,
+    BidRejection,
+    BidSummary,
+    Envelope,
+    MessageType,
+    Query,
+    QueryReply,
+    RejectionReason,
+    ValidatedBid
 };
+use shared::rpc::serve;
+use shared::signing::{self, VerifyingKey};
+use shared::topology::{
+    auction_srv_instance,
+    auction_srv_queue,
+    client_routing_key,
+    ENCERRAMENTO_SOLICITADO,
+    BID_SRV_LEILAO_CANCELADO,
+    BID_SRV_LEILAO_INICIADO,
+    BID_SRV_LEILAO_PRORROGADO,
+    CONSULTA_LANCES,
+    REGISTRO_CHAVES,
+    LANCE_REALIZADO,
+    LANCE_REJEITADO,
+    LANCE_VALIDADO,
+    LEILAO_FINALIZADO,
+    LEILAO_VENCEDOR,
+    PRORROGACAO_SOLICITADA
+};
+use crate::keys::KeyRegistry;
+use crate::ledger::{BidLedger, LedgerEntry};
+use crate::replay::{ReplayGuard, REPLAY_WINDOW_MS};
 
 /*==================================================== TASKS  ====================================================*/
 
 pub async fn task_end_auction(
     auctions: Arc<Mutex<Vec<Auction>>>,
     bids: Arc<Mutex<Vec<Bid>>>,
-    conn: Arc<Connection>,
+    ledger: Arc<Mutex<BidLedger>>,
+    broker: Arc<Broker>,
 ) {
-    let channel = conn.create_channel().await.unwrap();
-
-    let mut consumer = channel.basic_consume(
-        "leilao_finalizado", 
-        "bid-srv", 
-        BasicConsumeOptions::default(), 
-        FieldTable::default()
-    ).await.unwrap();
+    let publisher = broker.publisher();
+    let mut deliveries = broker.subscribe(LEILAO_FINALIZADO, "bid-srv");
 
-    while let Some(delivery) = consumer.next().await {
-        let delivery = delivery.unwrap();
-        delivery.ack(Default::default()).await.unwrap();
+    loop {
+        let (delivery, envelope) = deliveries
+            .next_envelope::<AuctionFinished>(MessageType::AuctionFinished)
+            .await;
 
-        let auction_id = u32::from_ne_bytes(delivery.data.as_slice().try_into().unwrap());
+        let auction_id = envelope.payload.auction_id;
         println!("Received delivery on leilao_finalizado: {auction_id}");
+
+        // auction-srv publishes again when it did not get the confirmation in time
+        if auctions.lock().await.iter().any(|a| a.id == auction_id && !a.status) {
+            println!("Ignoring duplicate {}", envelope.message_id);
+            if let Err(e) = delivery.ack(Default::default()).await {
+                println!("Failed to ack delivery on leilao_finalizado, it will be redelivered: {e}");
+            }
+            continue;
+        }
+
+        if let Err(e) = append_to_ledger(&ledger, LedgerEntry::AuctionFinished(auction_id)).await {
+            println!("Failed to write auction end to the ledger, retrying later: {e}");
+            deliveries.retry_later(delivery).await;
+            continue;
+        }
+
         let mut auctions = auctions.lock().await;
+        let mut finished = None;
         if let Some(auction) = auctions.iter_mut().find(|a| a.id == auction_id){
             auction.status = false;
+            finished = Some(auction.clone());
         }
         drop(auctions); //ensures lock is released before next iteration
 
-        //If there is a bid for this auction, publish the winner bid
+        //If there is a bid for this auction, publish the winner or why there is none
         let bids = bids.lock().await;
-        if let Some(winning_bid) = bids
-            .iter()
-            .filter(|a| a.auction_id == auction_id)
-            .max_by(|a, b| a.value.partial_cmp(&b.value).unwrap())
-        {
-            publish_winner_bid(&channel, winning_bid).await.unwrap();
+        let auction_bids: Vec<&Bid> = bids.iter().filter(|b| b.auction_id == auction_id).collect();
+        if let Some(outcome) = finished.and_then(|auction| auction.outcome(&auction_bids)) {
+            publish_outcome(&publisher, &outcome, &envelope.message_id).unwrap();
         }
         else{
             println!("No bid found for auction {auction_id}");
         }
         drop(bids); //ensures lock is released before next iteration
-            
+
+        if let Err(e) = delivery.ack(Default::default()).await {
+            println!("Failed to ack delivery on leilao_finalizado, it will be redelivered: {e}");
+        }
     }
 }
 
 pub async fn task_validate_bid(
     auctions: Arc<Mutex<Vec<Auction>>>,
     bids: Arc<Mutex<Vec<Bid>>>,
-    conn: Arc<Connection>,
+    ledger: Arc<Mutex<BidLedger>>,
+    keys: Arc<Mutex<KeyRegistry>>,
+    broker: Arc<Broker>,
 ) {
+    let publisher = broker.publisher();
+    let mut deliveries = broker.subscribe(LANCE_REALIZADO, "bid-srv");
+    let auction_srv_instances = broker.config().auction_srv_instances;
 
-    let channel = conn.create_channel().await.unwrap();
+    let mut replay_guard = ReplayGuard::new(REPLAY_WINDOW_MS);
+    replay_guard.remember(bids.lock().await.iter(), now_millis());
 
-    let mut consumer = channel.basic_consume(
-        "lance_realizado", 
-        "bid-srv", 
-        BasicConsumeOptions::default(), 
-        FieldTable::default()
-    ).await.unwrap();
+    loop {
+        let (delivery, envelope) = deliveries.next_envelope::<Bid>(MessageType::BidPlaced).await;
 
-    let public_keys = load_public_keys_vec("bid-srv/keys").unwrap();
-
-    while let Some(delivery) = consumer.next().await {
-        let delivery = delivery.unwrap();
-        delivery.ack(Default::default()).await.unwrap();
-
-        let bid: Bid = serde_json::from_slice(&delivery.data).unwrap();
+        let bid = envelope.payload;
         println!("Received delivery on lance_realizado");
         dbg!(&bid);
 
-        //let public_key = RsaPublicKey::from_public_key_pem(bid.public_key.as_str()).unwrap();
-        let public_key = public_keys.get(bid.client_id as usize).unwrap().clone().unwrap();
-        let bid_is_valid = is_bid_valid(
+        let public_key = keys.lock().await.lookup(bid.client_id);
+        let validation = validate_bid(
             &bid,
             &auctions,
             &bids,
-            public_key
+            public_key,
+            &mut replay_guard
         ).await;
-        if bid_is_valid {
-            let mut bids = bids.lock().await;
-            bids.push(bid.clone());
-            drop(bids); //ensures lock is released before next iteration
-
-            publish_validated_bid(&channel, &bid).await.unwrap();
-        } else {
-            println!("Bid was deemed invalid, if nothing else, because of signature verification failure" );
+        match validation {
+            Ok(auction) => {
+                if let Err(e) = accept_bid(&bid, &ledger, &bids, &mut replay_guard).await {
+                    println!("Failed to write bid to the ledger, retrying later: {e}");
+                    deliveries.retry_later(delivery).await;
+                    continue;
+                }
+
+                publish_validated_bid(&publisher, &bid, auction.kind, &envelope.message_id).unwrap();
+
+                if auction.kind == AuctionKind::Dutch {
+                    publish_close_request(&publisher, bid.auction_id, auction_srv_instances, &envelope.message_id).unwrap();
+                }
+                else if let Some(end_timestamp) = auction.soft_close_end(now_millis()) {
+                    publish_extension_request(
+                        &publisher,
+                        bid.auction_id,
+                        end_timestamp,
+                        auction_srv_instances,
+                        &envelope.message_id
+                    ).unwrap();
+                }
+            }
+            Err(reason) => {
+                println!("Bid was deemed invalid: {reason}");
+                publish_rejected_bid(&publisher, &bid, reason, &envelope.message_id).unwrap();
+            }
+        }
+
+        if let Err(e) = delivery.ack(Default::default()).await {
+            println!("Failed to ack delivery on lance_realizado, it will be redelivered: {e}");
         }
     }
 
@@ -117,103 +169,309 @@ pub async fn task_validate_bid(
 
 pub async fn task_init_auction(
     auctions: Arc<Mutex<Vec<Auction>>>,
-    conn: Arc<Connection>,
-    fo_queue_name: String,
+    ledger: Arc<Mutex<BidLedger>>,
+    broker: Arc<Broker>,
 ){
-    let channel = conn.create_channel().await.unwrap();
-    let mut consumer = channel.basic_consume(
-            fo_queue_name.as_str(), 
-            "bid-srv",
-            BasicConsumeOptions::default(), 
-            FieldTable::default()
-        )
-        .await
-        .unwrap();
+    let mut deliveries = broker.subscribe(BID_SRV_LEILAO_INICIADO, "bid-srv");
 
-    while let Some(delivery) = consumer.next().await {
-        let delivery = delivery.unwrap();
-        delivery.ack(Default::default()).await.unwrap();
+    loop {
+        let (delivery, envelope) = deliveries.next_envelope::<Auction>(MessageType::AuctionStarted).await;
 
-        let auction: Auction = serde_json::from_slice(delivery.data.as_ref()).unwrap();
+        let auction = envelope.payload;
         println!("Received delivery on leilao_iniciado: {}", auction.id);
 
+        let known = auctions.lock().await.iter().find(|a| a.id == auction.id).cloned();
+        if let Some(known) = known {
+            // the same auction again is a redelivery, another one with its id is a collision
+            if known.item == auction.item && known.start_timestamp == auction.start_timestamp {
+                println!("Ignoring duplicate {}", envelope.message_id);
+                if let Err(e) = delivery.ack(Default::default()).await {
+                    println!("Failed to ack delivery on leilao_iniciado, it will be redelivered: {e}");
+                }
+            }
+            else {
+                let reason = format!("auction id {} is already used by '{}'", auction.id, known.item);
+                println!("Rejecting auction '{}': {reason}", auction.item);
+                deliveries.dead_letter(delivery, &reason).await;
+            }
+            continue;
+        }
+
+        if let Err(e) = append_to_ledger(&ledger, LedgerEntry::AuctionStarted(auction.clone())).await {
+            println!("Failed to write auction start to the ledger, retrying later: {e}");
+            deliveries.retry_later(delivery).await;
+            continue;
+        }
+
         let mut auctions = auctions.lock().await;
         
         auctions.push(auction);
         drop(auctions); //ensures lock is released before next iteration
+
+        if let Err(e) = delivery.ack(Default::default()).await {
+            println!("Failed to ack delivery on leilao_iniciado, it will be redelivered: {e}");
+        }
     }
 }
 
-/*==================================================== TASKS - END ====================================================*/
+/// A cancelled auction is closed like a finished one, but nobody wins it.
+pub async fn task_cancel_auction(
+    auctions: Arc<Mutex<Vec<Auction>>>,
+    ledger: Arc<Mutex<BidLedger>>,
+    broker: Arc<Broker>,
+){
+    let mut deliveries = broker.subscribe(BID_SRV_LEILAO_CANCELADO, "bid-srv");
 
+    loop {
+        let (delivery, envelope) = deliveries
+            .next_envelope::<AuctionCancelled>(MessageType::AuctionCancelled)
+            .await;
 
-/*====================================================== AUX ====================================================== */
-fn load_public_keys_vec<P: AsRef<Path>>(folder: P) -> std::io::Result<Vec<Option<RsaPublicKey>>> {
-    let mut keys = Vec::new();
-
-    for entry in fs::read_dir(folder)? {
-        let entry = entry?;
-        let path = entry.path();
-
-        if let Some(filename) = path.file_name().and_then(|n| n.to_str()) {
-            if let Some(id_str) = filename.strip_prefix("client_") {
-                if let Ok(id) = id_str.parse::<usize>() {
-                    let pem = fs::read_to_string(&path)?;
-                    if let Ok(pub_key) = RsaPublicKey::from_public_key_pem(&pem) {
-                        if id >= keys.len() {
-                            keys.resize(id + 1, None);
-                        }
-                        keys[id] = Some(pub_key);
+        let auction_id = envelope.payload.auction_id;
+        println!("Received delivery on leilao_cancelado: {auction_id}");
+
+        if auctions.lock().await.iter().any(|a| a.id == auction_id && !a.status) {
+            println!("Ignoring duplicate {}", envelope.message_id);
+            if let Err(e) = delivery.ack(Default::default()).await {
+                println!("Failed to ack delivery on leilao_cancelado, it will be redelivered: {e}");
+            }
+            continue;
+        }
+
+        if let Err(e) = append_to_ledger(&ledger, LedgerEntry::AuctionCancelled(auction_id)).await {
+            println!("Failed to write auction cancellation to the ledger, retrying later: {e}");
+            deliveries.retry_later(delivery).await;
+            continue;
+        }
+
+        let mut auctions = auctions.lock().await;
+        if let Some(auction) = auctions.iter_mut().find(|a| a.id == auction_id){
+            auction.status = false;
+        }
+        drop(auctions); //ensures lock is released before next iteration
+
+        if let Err(e) = delivery.ack(Default::default()).await {
+            println!("Failed to ack delivery on leilao_cancelado, it will be redelivered: {e}");
+        }
+    }
+}
+
+pub async fn task_extend_auction(
+    auctions: Arc<Mutex<Vec<Auction>>>,
+    ledger: Arc<Mutex<BidLedger>>,
+    broker: Arc<Broker>,
+){
+    let mut deliveries = broker.subscribe(BID_SRV_LEILAO_PRORROGADO, "bid-srv");
+
+    loop {
+        let (delivery, envelope) = deliveries
+            .next_envelope::<AuctionExtended>(MessageType::AuctionExtended)
+            .await;
+
+        let AuctionExtended { auction_id, end_timestamp } = envelope.payload;
+        println!("Received delivery on leilao_prorrogado: {auction_id} until {end_timestamp}");
+
+        if auctions.lock().await.iter().any(|a| a.id == auction_id && a.end_timestamp == end_timestamp) {
+            println!("Ignoring duplicate {}", envelope.message_id);
+            if let Err(e) = delivery.ack(Default::default()).await {
+                println!("Failed to ack delivery on leilao_prorrogado, it will be redelivered: {e}");
+            }
+            continue;
+        }
+
+        let entry = LedgerEntry::AuctionExtended { auction_id, end_timestamp };
+        if let Err(e) = append_to_ledger(&ledger, entry).await {
+            println!("Failed to write auction extension to the ledger, retrying later: {e}");
+            deliveries.retry_later(delivery).await;
+            continue;
+        }
+
+        let mut auctions = auctions.lock().await;
+        if let Some(auction) = auctions.iter_mut().find(|a| a.id == auction_id){
+            auction.end_timestamp = end_timestamp;
+        }
+        drop(auctions); //ensures lock is released before next iteration
+
+        if let Err(e) = delivery.ack(Default::default()).await {
+            println!("Failed to ack delivery on leilao_prorrogado, it will be redelivered: {e}");
+        }
+    }
+}
+
+/// Answers `get-highest-bid` and bid history queries from the accepted bids.
+/// Open sealed auctions look like they have no bids yet.
+pub async fn task_serve_queries(
+    auctions: Arc<Mutex<Vec<Auction>>>,
+    bids: Arc<Mutex<Vec<Bid>>>,
+    broker: Arc<Broker>,
+){
+    serve(broker, CONSULTA_LANCES, "bid-srv", |query| {
+        let auctions = auctions.clone();
+        let bids = bids.clone();
+        async move {
+            // bids of a sealed auction stay secret until it closes
+            let sealed: Vec<u32> = auctions
+                .lock()
+                .await
+                .iter()
+                .filter(|a| a.status && a.kind.is_sealed())
+                .map(|a| a.id)
+                .collect();
+            let bids = bids.lock().await;
+            let auction_bids = |auction_id: u32| bids
+                .iter()
+                .filter(move |b| b.auction_id == auction_id && !sealed.contains(&auction_id));
+
+            match query {
+                Query::GetHighestBid { auction_id } => QueryReply::HighestBid(
+                    auction_bids(auction_id)
+                        .max_by_key(|b| b.value)
+                        .map(BidSummary::from)
+                ),
+                Query::GetBidHistory { auction_id } => QueryReply::BidHistory(
+                    auction_bids(auction_id).map(BidSummary::from).collect()
+                ),
+                _ => QueryReply::Unsupported,
+            }
+        }
+    }).await;
+}
+
+/// Registers, rotates and revokes client keys. Bids are checked against the
+/// registry as it is at the time, so changes apply to the next bid.
+pub async fn task_manage_keys(
+    keys: Arc<Mutex<KeyRegistry>>,
+    broker: Arc<Broker>,
+){
+    serve(broker, REGISTRO_CHAVES, "bid-srv", |query| {
+        let keys = keys.clone();
+        async move {
+            match query {
+                Query::Key(request) => {
+                    let result = keys.lock().await.apply(&request, now_millis());
+                    match &result {
+                        Ok(()) => println!("Applied {:?} for client {}", request.operation, request.client_id),
+                        Err(reason) => println!("Refused key request of client {}: {reason}", request.client_id),
                     }
+                    QueryReply::Key(result)
                 }
+                _ => QueryReply::Unsupported,
             }
         }
-    }
+    }).await;
+}
+
+/*==================================================== TASKS - END ====================================================*/
+
 
-    Ok(keys)
+/*====================================================== AUX ====================================================== */
+/// Writes the entry from a blocking thread, the fsync can stall the runtime for a while.
+async fn append_to_ledger(ledger: &Arc<Mutex<BidLedger>>, entry: LedgerEntry) -> std::io::Result<()> {
+    let mut ledger = ledger.clone().lock_owned().await;
+    tokio::task::spawn_blocking(move || ledger.append(&entry))
+        .await
+        .map_err(std::io::Error::other)?
 }
+
 /*============================================= PUBLISH ============================================= */
 
 
-async fn publish_validated_bid(
-    channel: &Channel, 
-    bid: &Bid
+fn publish_validated_bid(
+    publisher: &Publisher, 
+    bid: &Bid,
+    kind: AuctionKind,
+    placed_message_id: &str
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let payload = serde_json::to_vec(bid)?;
-    channel
-        .basic_publish(
-            "",
-            "lance_validado",
-            BasicPublishOptions::default(),
-            &payload,
-            lapin::BasicProperties::default(),
-        )
-        .await?
-        .await?;
+    let envelope = Envelope::new(MessageType::BidValidated, ValidatedBid { bid: bid.clone(), kind })
+        .correlated_with(placed_message_id);
+    publisher.publish_envelope(
+        "",
+        LANCE_VALIDADO,
+        &envelope,
+    )?;
     println!("Published Validated bid on lance_validado");
     dbg!(bid);
 
     Ok(())
 }
 
-async fn publish_winner_bid(
-    channel: &Channel, 
-    bid: &Bid
+/// Addressed to the bidder only, through its routing key on `lance_rejeitado`.
+fn publish_rejected_bid(
+    publisher: &Publisher, 
+    bid: &Bid,
+    reason: RejectionReason,
+    placed_message_id: &str
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let payload = serde_json::to_vec(bid)?;
-    channel
-        .basic_publish(
-            "",
-            "leilao_vencedor",
-            BasicPublishOptions::default(),
-            &payload,
-            lapin::BasicProperties::default(),
-        )
-        .await?
-        .await?;
-
-    println!("Published Winner bid on leilao_vencedor");
-    dbg!(bid);
+    let envelope = Envelope::new(MessageType::BidRejected, BidRejection::new(bid, reason))
+        .correlated_with(placed_message_id);
+    publisher.publish_envelope(
+        LANCE_REJEITADO,
+        &client_routing_key(bid.client_id),
+        &envelope,
+    )?;
+
+    println!("Published rejection to client {} on lance_rejeitado", bid.client_id);
+    Ok(())
+}
+
+/// auction-srv owns the schedule, so it decides whether the auction is still open
+/// and announces the new end on `leilao_prorrogado`. Only the instance that owns
+/// the auction knows it, so the request goes to that instance's queue.
+fn publish_extension_request(
+    publisher: &Publisher,
+    auction_id: u32,
+    end_timestamp: u128,
+    auction_srv_instances: u32,
+    placed_message_id: &str
+) -> Result<(), Box<dyn std::error::Error>> {
+    let envelope = Envelope::new(MessageType::ExtensionRequested, AuctionExtended { auction_id, end_timestamp })
+        .correlated_with(placed_message_id);
+    let queue = auction_srv_queue(PRORROGACAO_SOLICITADA, auction_srv_instance(auction_id, auction_srv_instances));
+    publisher.publish_envelope(
+        "",
+        &queue,
+        &envelope,
+    )?;
+
+    println!("Asked to extend auction {auction_id} until {end_timestamp} on {queue}");
+    Ok(())
+}
+
+/// A Dutch auction ends with its first bid; the auction-srv instance that owns it
+/// closes it as if its time was up.
+fn publish_close_request(
+    publisher: &Publisher,
+    auction_id: u32,
+    auction_srv_instances: u32,
+    placed_message_id: &str
+) -> Result<(), Box<dyn std::error::Error>> {
+    let envelope = Envelope::new(MessageType::CloseRequested, AuctionFinished { auction_id })
+        .correlated_with(placed_message_id);
+    let queue = auction_srv_queue(ENCERRAMENTO_SOLICITADO, auction_srv_instance(auction_id, auction_srv_instances));
+    publisher.publish_envelope(
+        "",
+        &queue,
+        &envelope,
+    )?;
+
+    println!("Asked to close auction {auction_id} on {queue}");
+    Ok(())
+}
+
+fn publish_outcome(
+    publisher: &Publisher, 
+    outcome: &AuctionOutcome,
+    finished_message_id: &str
+) -> Result<(), Box<dyn std::error::Error>> {
+    let envelope = Envelope::new(MessageType::AuctionOutcome, outcome)
+        .correlated_with(finished_message_id);
+    publisher.publish_envelope(
+        "",
+        LEILAO_VENCEDOR,
+        &envelope,
+    )?;
+
+    println!("Published auction outcome on leilao_vencedor");
 
     Ok(())
 }  
@@ -222,56 +480,127 @@ async fn publish_winner_bid(
 
 /*============================================= BID VERIFICATION ============================================= */
 
-async fn is_bid_valid(
+/// The signature is checked first, so only the owner of the key learns anything
+/// about the auction from a rejection. Returns the auction the bid was taken for.
+async fn validate_bid(
     bid: &Bid,
     auctions: &Arc<Mutex<Vec<Auction>>>,
     bids: &Arc<Mutex<Vec<Bid>>>,
-    public_key: RsaPublicKey
-) -> bool {
-    let auctions = auctions.lock().await;
-    let auction_opt = auctions.iter().find(|a| a.id == bid.auction_id && a.status);
+    public_key: Result<VerifyingKey, RejectionReason>,
+    replay_guard: &mut ReplayGuard
+) -> Result<Auction, RejectionReason> {
+    let public_key = public_key?;
+    if !verify_bid(bid, public_key) {
+        return Err(RejectionReason::BadSignature);
+    }
+    // only signed bids use up a nonce, so nobody else can burn those of a client;
+    // an accepted one is used up by `accept_bid`, once it is on the ledger
+    replay_guard.check(bid, now_millis())?;
 
-    if auction_opt.is_none() {
-        println!("Auction not found or inactive, bid invalid");
-        return false;
+    let checked = check_against_auction(bid, auctions, bids).await;
+    if checked.is_err() {
+        replay_guard.record(bid);
     }
+    checked
+}
 
-    let bids = bids.lock().await;
-    let highest_bid_opt = bids
+async fn check_against_auction(
+    bid: &Bid,
+    auctions: &Arc<Mutex<Vec<Auction>>>,
+    bids: &Arc<Mutex<Vec<Bid>>>
+) -> Result<Auction, RejectionReason> {
+    let auctions = auctions.lock().await;
+    let auction = auctions
         .iter()
-        .filter(|b| b.auction_id == bid.auction_id)
-        .max_by(|a, b| a.value.partial_cmp(&b.value).unwrap());
-
-    if let Some(highest_bid) = highest_bid_opt {
-        if bid.value <= highest_bid.value {
-            println!("Bid value {} is not higher than current highest bid {}, bid invalid", bid.value, highest_bid.value);
-            return false;
-        }
+        .find(|a| a.id == bid.auction_id)
+        .ok_or(RejectionReason::AuctionNotFound)?;
+    if !auction.status {
+        return Err(RejectionReason::AuctionClosed);
     }
 
-    print!("Verifying bid signature... {}", verify_bid(bid, public_key.clone()));
-    verify_bid(bid, public_key)
+    let bids = bids.lock().await;
+    let auction_bids: Vec<&Bid> = bids.iter().filter(|b| b.auction_id == bid.auction_id).collect();
 
+    auction.check_bid(bid, &auction_bids, now_millis())?;
+    Ok(auction.clone())
 }
 
-fn verify_bid(bid: &Bid, public_key: RsaPublicKey) -> bool {
-    let content = format!("{}:{}:{}", bid.auction_id, bid.client_id, bid.value).into_bytes();
-    let hashed = Sha256::digest(content);
+/// The bid only counts once it is on disk, so it survives a crash after publishing.
+/// Its nonce stays free until then, so a bid retried after a failed write is not a replay.
+async fn accept_bid(
+    bid: &Bid,
+    ledger: &Arc<Mutex<BidLedger>>,
+    bids: &Arc<Mutex<Vec<Bid>>>,
+    replay_guard: &mut ReplayGuard
+) -> std::io::Result<()> {
+    append_to_ledger(ledger, LedgerEntry::BidAccepted(bid.clone())).await?;
+    replay_guard.record(bid);
+    bids.lock().await.push(bid.clone());
+    Ok(())
+}
 
-    let thing: String = hashed.iter().map(|b| format!("{:02x}", b)).collect();
-    println!("verifying->{}:{}:{}\n{}", bid.auction_id, bid.client_id, bid.value, thing);
+pub(crate) fn now_millis() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
 
-    let signature_bytes = match general_purpose::STANDARD.decode(&bid.signature) {
-        Ok(sig) => sig,
-        Err(_) => {
-            println!("Failed to decode base64 signature");
-            return false;
-        }
-    };
+fn verify_bid(bid: &Bid, public_key: VerifyingKey) -> bool {
+    println!("verifying->{} ({:?})", String::from_utf8_lossy(&bid.signed_payload()), bid.signature_scheme);
 
-    public_key
-        .verify(Pkcs1v15Sign::new_unprefixed(), &hashed, &signature_bytes)
-        .is_ok()
+    match signing::verify_bid(bid, &public_key) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Signature check failed: {e}");
+            false
+        }
+    }
 }
 
 /*============================================= BID VERIFICATION - END ============================================= */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::signing::{SignatureScheme, SigningKey};
+
+    #[tokio::test]
+    async fn bid_that_failed_to_reach_the_ledger_is_taken_when_redelivered() {
+        let key = SigningKey::from_pem(include_str!("../../auction-client/keys/client_0")).unwrap();
+        let mut bid = Bid {
+            auction_id: 3,
+            client_id: 1,
+            value: "12.50".parse().unwrap(),
+            signature: String::new(),
+            public_key: String::new(),
+            valid: false,
+            quantity: 1,
+            nonce: 7,
+            timestamp: now_millis(),
+            signature_scheme: Default::default(),
+        };
+        signing::sign_bid(&mut bid, SignatureScheme::RsaPkcs1v15Sha256, &key).unwrap();
+
+        let now = now_millis();
+        let auctions = Arc::new(Mutex::new(vec![Auction::new(3, "lamp".to_string(), now, now + 60_000)]));
+        let bids = Arc::new(Mutex::new(Vec::new()));
+        let mut replay_guard = ReplayGuard::new(REPLAY_WINDOW_MS);
+        let validate = async |replay_guard: &mut ReplayGuard| {
+            validate_bid(&bid, &auctions, &bids, Ok(key.verifying_key()), replay_guard).await.map(|_| ())
+        };
+
+        // every write to /dev/full fails with ENOSPC
+        let full = Arc::new(Mutex::new(BidLedger::open("/dev/full").unwrap()));
+        assert_eq!(validate(&mut replay_guard).await, Ok(()));
+        assert!(accept_bid(&bid, &full, &bids, &mut replay_guard).await.is_err());
+        assert!(bids.lock().await.is_empty());
+
+        let path = std::env::temp_dir().join(format!("bid-srv-accept-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let ledger = Arc::new(Mutex::new(BidLedger::open(&path).unwrap()));
+        assert_eq!(validate(&mut replay_guard).await, Ok(()));
+        accept_bid(&bid, &ledger, &bids, &mut replay_guard).await.unwrap();
+        assert_eq!(bids.lock().await.len(), 1);
+        assert_eq!(validate(&mut replay_guard).await, Err(RejectionReason::ReplayedBid));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+use std::env;
+
+use lapin::{
+    message::BasicGetMessage,
+    options::{BasicAckOptions, BasicGetOptions, BasicPublishOptions, ConfirmSelectOptions},
+    BasicProperties,
+    Channel,
+};
+
+use shared::config::BrokerConfig;
+use shared::dead_letter::{
+    header,
+    replay_properties,
+    HEADER_EXCHANGE,
+    HEADER_FAILED_AT,
+    HEADER_ID,
+    HEADER_QUEUE,
+    HEADER_REASON,
+    HEADER_ROUTING_KEY
+};
+use shared::topology::{declare_topology, MENSAGENS_MORTAS};
+
+const PREVIEW_LEN: usize = 200;
+
+/// Inspects and replays the messages the services moved to `mensagens_mortas`.
+///
+/// Messages are fetched without being acked, so everything that is only listed,
+/// or not selected for replay, goes back to the queue when the tool exits.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (broker_config, args) = BrokerConfig::from_args(env::args().collect())?;
+    let command: Vec<&str> = args.iter().skip(1).map(String::as_str).collect();
+
+    let conn = broker_config.connect().await?;
+    let channel = conn.create_channel().await?;
+    declare_topology(&channel, broker_config.auction_srv_instances).await?;
+
+    match command.as_slice() {
+        ["list"] => list(&channel).await?,
+        ["replay", "--all"] => replay(&channel, None).await?,
+        ["replay", id] => replay(&channel, Some(id)).await?,
+        _ => {
+            eprintln!("Usage: {} list | replay <id> | replay --all [--amqp-url <url>] [--amqp-config <file>]", args[0]);
+            std::process::exit(1);
+        }
+    }
+
+    let _ = conn.close(200, "done").await;
+    Ok(())
+}
+
+async fn list(channel: &Channel) -> Result<(), lapin::Error> {
+    let messages = fetch_all(channel).await?;
+    if messages.is_empty() {
+        println!("{MENSAGENS_MORTAS} is empty");
+    }
+
+    for message in &messages {
+        print_message(message);
+    }
+
+    Ok(())
+}
+
+/// Publishes the selected messages straight back to the queue they were consumed
+/// from, without the dead-letter headers, and removes them from `mensagens_mortas`.
+async fn replay(channel: &Channel, id: Option<&str>) -> Result<(), lapin::Error> {
+    channel.confirm_select(ConfirmSelectOptions::default()).await?;
+
+    let mut replayed = 0;
+    for message in fetch_all(channel).await? {
+        if let Some(id) = id
+            && message_id(&message).as_deref() != Some(id) {
+            continue;
+        }
+
+        let Some(queue) = header(&message.delivery.properties, HEADER_QUEUE) else {
+            println!("Skipping {}: it has no {HEADER_QUEUE} header", message_id(&message).unwrap_or_default());
+            continue;
+        };
+
+        channel
+            .basic_publish(
+                "",
+                &queue,
+                BasicPublishOptions::default(),
+                &message.delivery.data,
+                replay_properties(&message.delivery.properties),
+            )
+            .await?
+            .await?;
+        message.delivery.ack(BasicAckOptions::default()).await?;
+
+        println!("Replayed {} to {queue}", message_id(&message).unwrap_or_default());
+        replayed += 1;
+    }
+
+    if replayed == 0 && let Some(id) = id {
+        println!("No message with id {id} in {MENSAGENS_MORTAS}");
+    }
+
+    Ok(())
+}
+
+/// Gets every message currently in the queue. They stay unacked, so the broker
+/// does not hand the same one out twice.
+async fn fetch_all(channel: &Channel) -> Result<Vec<BasicGetMessage>, lapin::Error> {
+    let mut messages = Vec::new();
+    while let Some(message) = channel.basic_get(MENSAGENS_MORTAS, BasicGetOptions::default()).await? {
+        messages.push(message);
+    }
+
+    Ok(messages)
+}
+
+/// Id added when the message was dead-lettered, falling back to the AMQP message id
+/// for messages the broker dead-lettered on its own.
+fn message_id(message: &BasicGetMessage) -> Option<String> {
+    properties_id(&message.delivery.properties)
+}
+
+fn properties_id(properties: &BasicProperties) -> Option<String> {
+    header(properties, HEADER_ID)
+        .or_else(|| properties.message_id().as_ref().map(|id| id.to_string()))
+}
+
+fn print_message(message: &BasicGetMessage) {
+    let properties = &message.delivery.properties;
+    let field = |name: &str| header(properties, name).unwrap_or_else(|| "-".to_string());
+
+    let body = String::from_utf8_lossy(&message.delivery.data);
+    let preview: String = body.chars().take(PREVIEW_LEN).collect();
+
+    println!("id:          {}", message_id(message).unwrap_or_else(|| "-".to_string()));
+    println!("reason:      {}", field(HEADER_REASON));
+    println!("queue:       {}", field(HEADER_QUEUE));
+    println!("published:   '{}' / '{}'", field(HEADER_EXCHANGE), field(HEADER_ROUTING_KEY));
+    println!("failed at:   {}", field(HEADER_FAILED_AT));
+    println!("body:        {preview}{}", if body.chars().count() > PREVIEW_LEN { "..." } else { "" });
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::types::{AMQPValue, FieldTable, LongString, ShortString};
+
+    #[test]
+    fn messages_are_picked_by_dead_letter_id_then_message_id() {
+        let plain = BasicProperties::default().with_message_id(ShortString::from("m-1"));
+        assert_eq!(properties_id(&plain).as_deref(), Some("m-1"));
+        assert_eq!(properties_id(&BasicProperties::default()), None);
+
+        let mut headers = FieldTable::default();
+        headers.insert(ShortString::from(HEADER_ID), AMQPValue::LongString(LongString::from("d-1")));
+        assert_eq!(properties_id(&plain.with_headers(headers)).as_deref(), Some("d-1"));
+    }
+}
@@ -1,89 +1,209 @@
-use futures_lite::StreamExt;
-use lapin::{options::{BasicAckOptions, BasicConsumeOptions, BasicPublishOptions}, types::FieldTable, BasicProperties, Channel, Connection};
+use lapin::options::BasicAckOptions;
 use std::{error::Error, sync::Arc};
 
-use shared::models::{Bid, Notification, NotificationType};
-
-
+use shared::broker::{Broker, Publisher};
+use shared::models::{
+    AuctionCancelled,
+    AuctionExtended,
+    AuctionOutcome,
+    Envelope,
+    MessageType,
+    Notification,
+    NotificationType,
+    ValidatedBid
+};
+use shared::topology::{
+    auction_routing_key,
+    LANCE_VALIDADO,
+    LEILAO_VENCEDOR,
+    NOTIFICACOES,
+    NOTIFICATION_SRV_LEILAO_CANCELADO,
+    NOTIFICATION_SRV_LEILAO_PRORROGADO
+};
+
+
+
+pub async fn task_notify_bid(broker: Arc<Broker>){
+    let publisher = broker.publisher();
+    let mut deliveries = broker.subscribe(LANCE_VALIDADO, "notification-srv");
+
+    loop{
+        let (delivery, envelope) = deliveries.next_envelope::<ValidatedBid>(MessageType::BidValidated).await;
+
+        let Some(notification) = bid_notification(&envelope.payload) else {
+            println!("Not broadcasting bid {} on sealed auction {}", envelope.message_id, envelope.payload.bid.auction_id);
+            if let Err(e) = delivery.ack(BasicAckOptions::default()).await{
+                println!("Failed to ack delivery on lance_validado, it will be redelivered: {e}");
+            }
+            continue;
+        };
+        if let Err(e) = publish_notification(&publisher, notification, &envelope.message_id)
+            .map_err(|e| e.to_string()) {
+            deliveries.dead_letter(delivery, &e).await;
+            continue;
+        }
+
+        if let Err(e) = delivery.ack(BasicAckOptions::default()).await{
+            println!("Failed to ack delivery on lance_validado, it will be redelivered: {e}");
+        }
+    }
+}
 
-pub async fn task_notify_bid(conn: Arc<Connection>){
-    let channel = conn.create_channel().await.unwrap();
+pub async fn task_notify_winner(broker: Arc<Broker>){
+    let publisher = broker.publisher();
+    let mut deliveries = broker.subscribe(LEILAO_VENCEDOR, "notification-srv");
 
-    let mut consumer = channel.basic_consume(
-        "lance_validado", 
-        "notification-srv", 
-        BasicConsumeOptions::default(), 
-        FieldTable::default()
-    )
-    .await.unwrap();
+    loop{
+        let (delivery, envelope) = deliveries.next_envelope::<AuctionOutcome>(MessageType::AuctionOutcome).await;
 
-    while let Some(delivery) = consumer.next().await{
-        let delivery = delivery.unwrap();
-        delivery.ack(BasicAckOptions::default()).await.unwrap();
-        let bid: Bid = serde_json::from_slice(&delivery.data).unwrap();
+        if let Err(e) = outcome_notifications(&envelope.payload)
+            .into_iter()
+            .try_for_each(|notification| publish_notification(&publisher, notification, &envelope.message_id))
+            .map_err(|e| e.to_string()) {
+            deliveries.dead_letter(delivery, &e).await;
+            continue;
+        }
 
-        publish_to_notification_exc(
-            &channel, 
-            &bid,
-            NotificationType::NewBid
-        ).await.unwrap();
+        if let Err(e) = delivery.ack(BasicAckOptions::default()).await{
+            println!("Failed to ack delivery on leilao_vencedor, it will be redelivered: {e}");
+        }
     }
 }
 
-pub async fn task_notify_winner(conn: Arc<Connection>){
-    let channel = conn.create_channel().await.unwrap();
-
-    let mut consumer = channel.basic_consume(
-        "leilao_vencedor", 
-        "notification-srv",
-        BasicConsumeOptions::default(), 
-        FieldTable::default()
-    ).await.unwrap();
-
-    while let Some(delivery) = consumer.next().await{
-        let delivery = delivery.unwrap();
-        delivery.ack(BasicAckOptions::default()).await.unwrap();
-
-        let bid: Bid = serde_json::from_slice(&delivery.data).unwrap();
+pub async fn task_notify_cancelled(broker: Arc<Broker>){
+    let publisher = broker.publisher();
+    let mut deliveries = broker.subscribe(NOTIFICATION_SRV_LEILAO_CANCELADO, "notification-srv");
+
+    loop{
+        let (delivery, envelope) = deliveries
+            .next_envelope::<AuctionCancelled>(MessageType::AuctionCancelled)
+            .await;
+
+        let notification = Notification::auction_cancelled(envelope.payload.auction_id);
+        if let Err(e) = publish_notification(&publisher, notification, &envelope.message_id)
+            .map_err(|e| e.to_string()) {
+            deliveries.dead_letter(delivery, &e).await;
+            continue;
+        }
+
+        if let Err(e) = delivery.ack(BasicAckOptions::default()).await{
+            println!("Failed to ack delivery on leilao_cancelado, it will be redelivered: {e}");
+        }
+    }
+}
 
-        publish_to_notification_exc(
-            &channel, 
-            &bid, 
-            NotificationType::AuctionWinner
-        ).await.unwrap();
+pub async fn task_notify_extended(broker: Arc<Broker>){
+    let publisher = broker.publisher();
+    let mut deliveries = broker.subscribe(NOTIFICATION_SRV_LEILAO_PRORROGADO, "notification-srv");
+
+    loop{
+        let (delivery, envelope) = deliveries
+            .next_envelope::<AuctionExtended>(MessageType::AuctionExtended)
+            .await;
+
+        let notification = Notification::auction_extended(
+            envelope.payload.auction_id,
+            envelope.payload.end_timestamp
+        );
+        if let Err(e) = publish_notification(&publisher, notification, &envelope.message_id)
+            .map_err(|e| e.to_string()) {
+            deliveries.dead_letter(delivery, &e).await;
+            continue;
+        }
+
+        if let Err(e) = delivery.ack(BasicAckOptions::default()).await{
+            println!("Failed to ack delivery on leilao_prorrogado, it will be redelivered: {e}");
+        }
+    }
+}
 
+/*====================================================== AUX ====================================================== */
 
+/// Bids of sealed auctions stay secret, the outcome is announced when they close.
+fn bid_notification(validated: &ValidatedBid) -> Option<Notification>{
+    if validated.kind.is_sealed() {
+        return None;
     }
+    Some(Notification::from_bid(&validated.bid, NotificationType::NewBid))
 }
 
-/*====================================================== AUX ====================================================== */
+/// One notification per winner of a multi-unit auction, or one for the bid that
+/// missed the reserve.
+fn outcome_notifications(outcome: &AuctionOutcome) -> Vec<Notification>{
+    match outcome {
+        AuctionOutcome::Winners(awards) => awards.iter().map(Notification::auction_won).collect(),
+        AuctionOutcome::ReserveNotMet { highest_bid, .. } => vec![Notification::from_bid(highest_bid, NotificationType::ReserveNotMet)],
+    }
+}
 
 /*============================================= PUBLISH ============================================= */
 
-async fn publish_to_notification_exc(channel: &Channel, bid: &Bid, notification_type: NotificationType) -> Result<(), Box<dyn Error>>{
-    let notification = Notification::from_bid(
-        bid,
-        notification_type
-    );
-    let routing_key: String = build_routing_key(notification.get_auction_id());
-    let payload = serde_json::to_vec(&notification).unwrap();
+fn publish_notification(publisher: &Publisher, notification: Notification, source_message_id: &str) -> Result<(), Box<dyn Error>>{
+    let routing_key: String = auction_routing_key(notification.get_auction_id());
+    let envelope = Envelope::new(MessageType::Notification, notification)
+        .correlated_with(source_message_id);
 
-    channel.basic_publish(
-        "notificacoes",
+    publisher.publish_envelope(
+        NOTIFICACOES,
         routing_key.as_str(),
-        BasicPublishOptions::default(), 
-        &payload, 
-        BasicProperties::default()
-    ).await?.await?;
+        &envelope
+    )?;
 
     println!("Published notification to notificacoes using routing key '{}'", routing_key);
-    dbg!(&notification);
 
     Ok(())
 }
 
 /*============================================= PUBLISH - END ============================================= */
 
-fn build_routing_key(auction_id: u32) -> String{
-    format!("leilao_{auction_id}")
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::models::{AuctionKind, Award, Bid};
+
+    fn bid(client_id: u32, value: &str, quantity: u32) -> Bid {
+        Bid {
+            auction_id: 4,
+            client_id,
+            value: value.parse().unwrap(),
+            signature: String::new(),
+            public_key: String::new(),
+            valid: true,
+            quantity,
+            nonce: 0,
+            timestamp: 0,
+            signature_scheme: Default::default(),
+        }
+    }
+
+    #[test]
+    fn bids_on_sealed_auctions_are_not_broadcast() {
+        let open = ValidatedBid { bid: bid(1, "10", 1), kind: AuctionKind::English };
+        let notification = bid_notification(&open).unwrap();
+        assert!(matches!(notification.get_notification_type(), NotificationType::NewBid));
+        assert_eq!(notification.get_auction_id(), 4);
+
+        for kind in [AuctionKind::SealedFirstPrice, AuctionKind::SealedSecondPrice] {
+            assert!(bid_notification(&ValidatedBid { bid: bid(1, "10", 1), kind }).is_none());
+        }
+    }
+
+    #[test]
+    fn every_award_gets_one_winner_notification() {
+        let awards = vec![
+            Award { bid: bid(1, "12", 2), quantity: 2, price: "10".parse().unwrap() },
+            Award { bid: bid(2, "11", 3), quantity: 1, price: "10".parse().unwrap() },
+        ];
+        let notifications = outcome_notifications(&AuctionOutcome::Winners(awards));
+        assert_eq!(notifications.len(), 2);
+        assert!(notifications.iter().all(|n| matches!(n.get_notification_type(), NotificationType::AuctionWinner)));
+        assert_eq!(notifications.iter().map(|n| (n.get_client_id(), n.get_quantity())).collect::<Vec<_>>(), vec![(1, 2), (2, 1)]);
+        assert!(notifications.iter().all(|n| n.get_bid_value().to_string() == "10.00 BRL"));
+
+        let missed = AuctionOutcome::ReserveNotMet { highest_bid: bid(3, "5", 1), reserve_price: "50".parse().unwrap() };
+        let notifications = outcome_notifications(&missed);
+        assert_eq!(notifications.len(), 1);
+        assert!(matches!(notifications[0].get_notification_type(), NotificationType::ReserveNotMet));
+        assert_eq!(notifications[0].get_client_id(), 3);
+    }
+}
@@ -0,0 +1,458 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_lite::StreamExt;
+use lapin::{
+    message::Delivery,
+    options::{BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions, ConfirmSelectOptions},
+    types::{FieldTable, ShortString},
+    BasicProperties, Channel, Connection, Consumer,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::{
+    config::BrokerConfig,
+    dead_letter::dead_letter_properties,
+    models::{Envelope, MessageType},
+    topology::{declare_topology, verify_topology, MENSAGENS_MORTAS},
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type ConnectHook = Arc<dyn Fn(Channel) -> BoxFuture<lapin::Result<()>> + Send + Sync>;
+
+/*==================================================== BROKER ====================================================*/
+
+/// Supervised connection to RabbitMQ.
+///
+/// A background task keeps the connection alive: whenever it drops, the task
+/// reconnects with exponential backoff, redeclares the shared topology and runs
+/// the hooks registered with [`Broker::on_connect`] before handing the new
+/// connection out. Tasks get channels and consumers through the broker instead
+/// of holding a `Connection`, and simply ask again when theirs fail.
+pub struct Broker {
+    config: BrokerConfig,
+    connection: watch::Sender<Option<Arc<Connection>>>,
+    hooks: Mutex<Vec<ConnectHook>>,
+}
+
+impl Broker {
+    pub fn new(config: BrokerConfig) -> Arc<Self> {
+        Arc::new(Broker {
+            config,
+            connection: watch::Sender::new(None),
+            hooks: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Registers a hook that runs on every (re)connection, after the topology
+    /// was declared. Used for per-process queues and bindings.
+    pub fn on_connect<F, Fut>(&self, hook: F)
+    where
+        F: Fn(Channel) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = lapin::Result<()>> + Send + 'static,
+    {
+        self.hooks.lock().unwrap().push(Arc::new(move |channel| Box::pin(hook(channel))));
+    }
+
+    /// Spawns the supervisor and waits for the first connection.
+    pub async fn start(self: &Arc<Self>) {
+        tokio::spawn(self.clone().supervise());
+        self.connection().await;
+    }
+
+    /// Waits until the broker is connected and returns the current connection.
+    pub async fn connection(&self) -> Arc<Connection> {
+        let mut rx = self.connection.subscribe();
+        let connection = rx
+            .wait_for(|connection| connection.is_some())
+            .await
+            .expect("the broker owns the sender");
+
+        connection.clone().expect("checked by wait_for")
+    }
+
+    /// Opens a channel, waiting for a reconnection if needed.
+    pub async fn create_channel(&self) -> Channel {
+        loop {
+            match self.connection().await.create_channel().await {
+                Ok(channel) => return channel,
+                Err(e) => {
+                    println!("Failed to open a channel ({e}), retrying");
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Starts consuming `queue` on a fresh channel, waiting for a reconnection if needed.
+    /// The channel is returned so the caller keeps it open while consuming.
+    pub async fn consume(&self, queue: &str, consumer_tag: &str) -> (Channel, Consumer) {
+        loop {
+            let channel = self.create_channel().await;
+            match channel
+                .basic_consume(queue, consumer_tag, BasicConsumeOptions::default(), FieldTable::default())
+                .await
+            {
+                Ok(consumer) => return (channel, consumer),
+                Err(e) => {
+                    println!("Failed to consume from {queue} ({e}), retrying");
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Returns an endless stream of deliveries from `queue` that resubscribes
+    /// on its own after a channel or connection failure.
+    pub fn subscribe(self: &Arc<Self>, queue: &str, consumer_tag: &str) -> Subscription {
+        Subscription {
+            broker: self.clone(),
+            queue: queue.to_string(),
+            consumer_tag: consumer_tag.to_string(),
+            current: None,
+        }
+    }
+
+    pub fn config(&self) -> &BrokerConfig {
+        &self.config
+    }
+
+    /// Returns a handle to a new background publisher bound to this broker.
+    pub fn publisher(self: &Arc<Self>) -> Publisher {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(task_publisher(self.clone(), rx));
+        Publisher { tx }
+    }
+
+    async fn supervise(self: Arc<Self>) {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            match self.establish().await {
+                Ok((connection, mut lost_rx)) => {
+                    backoff = INITIAL_BACKOFF;
+                    println!("Connected to RabbitMQ at {}", self.config);
+                    self.connection.send_replace(Some(Arc::new(connection)));
+
+                    let reason = lost_rx
+                        .recv()
+                        .await
+                        .map(|e| e.to_string())
+                        .unwrap_or_else(|| "connection dropped".to_string());
+                    self.connection.send_replace(None);
+                    println!("Lost connection to RabbitMQ ({reason}), reconnecting");
+                }
+                Err(e) => {
+                    println!("Could not connect to RabbitMQ ({e}), retrying in {backoff:?}");
+                    tokio::time::sleep(backoff).await;
+                    backoff = next_backoff(backoff);
+                }
+            }
+        }
+    }
+
+    async fn establish(&self) -> Result<(Connection, mpsc::UnboundedReceiver<lapin::Error>), String> {
+        let connection = self.config.connect().await.map_err(|e| e.to_string())?;
+
+        let (lost_tx, lost_rx) = mpsc::unbounded_channel();
+        connection.on_error(move |e| {
+            let _ = lost_tx.send(e);
+        });
+
+        if let Err(e) = self.prepare(&connection).await {
+            let _ = connection.close(200, "setup failed").await;
+            return Err(e);
+        }
+
+        Ok((connection, lost_rx))
+    }
+
+    async fn prepare(&self, connection: &Connection) -> Result<(), String> {
+        verify_topology(connection, self.config.auction_srv_instances).await.map_err(|e| e.to_string())?;
+
+        let channel = connection.create_channel().await.map_err(|e| e.to_string())?;
+        declare_topology(&channel, self.config.auction_srv_instances).await.map_err(|e| e.to_string())?;
+
+        let hooks = self.hooks.lock().unwrap().clone();
+        for hook in hooks {
+            hook(channel.clone()).await.map_err(|e| e.to_string())?;
+        }
+
+        let _ = channel.close(200, "setup done").await;
+        Ok(())
+    }
+}
+
+/*==================================================== SUBSCRIPTION ====================================================*/
+
+pub struct Subscription {
+    broker: Arc<Broker>,
+    queue: String,
+    consumer_tag: String,
+    current: Option<(Channel, Consumer)>,
+}
+
+impl Subscription {
+    /// Waits for the next delivery, re-creating the consumer whenever it fails.
+    pub async fn next(&mut self) -> Delivery {
+        loop {
+            let (_, consumer) = match &mut self.current {
+                Some(current) => current,
+                None => self.current.insert(self.broker.consume(&self.queue, &self.consumer_tag).await),
+            };
+
+            match consumer.next().await {
+                Some(Ok(delivery)) => return delivery,
+                Some(Err(e)) => println!("Consumer on {} failed ({e}), resubscribing", self.queue),
+                None => println!("Consumer on {} was cancelled, resubscribing", self.queue),
+            }
+            self.current = None;
+        }
+    }
+
+    /// Waits for the next message of type `expected`. Messages that cannot be
+    /// decoded, such as ones from a newer schema version, are dead-lettered and
+    /// skipped. The delivery is returned unacknowledged: ack it once processed.
+    pub async fn next_envelope<T: DeserializeOwned>(&mut self, expected: MessageType) -> (Delivery, Envelope<T>) {
+        loop {
+            let delivery = self.next().await;
+            match Envelope::decode(&delivery.data, expected) {
+                Ok(envelope) => return (delivery, envelope),
+                Err(e) => self.dead_letter(delivery, &e.to_string()).await,
+            }
+        }
+    }
+
+    /// Moves a poison message to `mensagens_mortas`, with `reason` and its origin in
+    /// the headers, then acks it. If the copy cannot be published the message is
+    /// nacked instead, and the queue's dead-letter exchange takes it without the headers.
+    pub async fn dead_letter(&mut self, delivery: Delivery, reason: &str) {
+        println!("Dead-lettering message on {}: {reason}", self.queue);
+
+        let properties = dead_letter_properties(&delivery, &self.queue, reason);
+        let published = match &self.current {
+            Some((channel, _)) => {
+                let message = OutgoingMessage {
+                    exchange: MENSAGENS_MORTAS.to_string(),
+                    routing_key: String::new(),
+                    payload: delivery.data.clone(),
+                    properties,
+                    confirmed: None,
+                };
+                publish(channel, &message).await
+            }
+            None => Err("no open channel".to_string()),
+        };
+
+        let settled = match published {
+            Ok(()) => delivery.ack(BasicAckOptions::default()).await,
+            Err(e) => {
+                println!("Failed to publish to {MENSAGENS_MORTAS} ({e}), nacking instead");
+                delivery.nack(BasicNackOptions { requeue: false, ..BasicNackOptions::default() }).await
+            }
+        };
+        if let Err(e) = settled {
+            println!("Failed to settle dead-lettered message on {} ({e}), it will be redelivered", self.queue);
+        }
+    }
+
+    /// Returns a message to its queue for another attempt, for failures that are
+    /// not the message's fault (e.g. the disk is full).
+    pub async fn retry_later(&self, delivery: Delivery) {
+        tokio::time::sleep(RETRY_DELAY).await;
+        if let Err(e) = delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await {
+            println!("Failed to requeue message on {} ({e}), it will be redelivered", self.queue);
+        }
+    }
+}
+
+/*==================================================== PUBLISHER ====================================================*/
+
+struct OutgoingMessage {
+    exchange: String,
+    routing_key: String,
+    payload: Vec<u8>,
+    properties: BasicProperties,
+    confirmed: Option<oneshot::Sender<()>>,
+}
+
+/// Cheap to clone handle that queues messages for the broker's publisher task.
+/// Messages published while disconnected stay buffered, in order, until the
+/// connection is back. The publisher channel runs in confirm mode and a message
+/// only leaves the buffer once the broker acknowledged it, so anything nacked or
+/// lost with the connection is published again.
+#[derive(Clone)]
+pub struct Publisher {
+    tx: mpsc::UnboundedSender<OutgoingMessage>,
+}
+
+/// Resolves once the broker confirmed the message. Confirmations of one
+/// [`Publisher`] arrive in the order its messages were published.
+pub struct PendingConfirm {
+    rx: oneshot::Receiver<()>,
+}
+
+impl PendingConfirm {
+    /// Returns false if the publisher task went away before the confirmation.
+    pub async fn confirmed(self) -> bool {
+        self.rx.await.is_ok()
+    }
+}
+
+impl Publisher {
+    pub fn publish(&self, exchange: &str, routing_key: &str, payload: Vec<u8>, properties: BasicProperties) -> PendingConfirm {
+        let (confirmed, rx) = oneshot::channel();
+        let message = OutgoingMessage {
+            exchange: exchange.to_string(),
+            routing_key: routing_key.to_string(),
+            payload,
+            properties,
+            confirmed: Some(confirmed),
+        };
+
+        if self.tx.send(message).is_err() {
+            println!("Publisher task is gone, dropping message for '{exchange}'/'{routing_key}'");
+        }
+
+        PendingConfirm { rx }
+    }
+
+    /// Publishes an envelope, mirroring its metadata in the AMQP properties so it
+    /// shows up in the management UI without decoding the body.
+    pub fn publish_envelope<T: Serialize>(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        envelope: &Envelope<T>,
+    ) -> Result<PendingConfirm, serde_json::Error> {
+        Ok(self.publish(exchange, routing_key, envelope.encode()?, envelope_properties(envelope)))
+    }
+}
+
+/// Doubles the wait between connection attempts, up to `MAX_BACKOFF`.
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_BACKOFF)
+}
+
+pub(crate) fn envelope_properties<T>(envelope: &Envelope<T>) -> BasicProperties {
+    let mut properties = BasicProperties::default()
+        .with_content_type(ShortString::from("application/json"))
+        // persistent, so queued messages survive a broker restart
+        .with_delivery_mode(2)
+        .with_message_id(ShortString::from(envelope.message_id.as_str()))
+        .with_type(ShortString::from(format!("{:?}", envelope.message_type)))
+        .with_timestamp((envelope.timestamp / 1000) as u64);
+    if let Some(correlation_id) = &envelope.correlation_id {
+        properties = properties.with_correlation_id(ShortString::from(correlation_id.as_str()));
+    }
+
+    properties
+}
+
+async fn task_publisher(broker: Arc<Broker>, mut rx: mpsc::UnboundedReceiver<OutgoingMessage>) {
+    let mut pending: VecDeque<OutgoingMessage> = VecDeque::new();
+    let mut channel: Option<Channel> = None;
+
+    loop {
+        if pending.is_empty() {
+            match rx.recv().await {
+                Some(message) => pending.push_back(message),
+                None => return,
+            }
+        }
+        while let Ok(message) = rx.try_recv() {
+            pending.push_back(message);
+        }
+
+        let current = match &channel {
+            Some(current) if current.status().connected() => current.clone(),
+            _ => {
+                let current = broker.create_channel().await;
+                if let Err(e) = current.confirm_select(ConfirmSelectOptions::default()).await {
+                    println!("Failed to enable publisher confirms ({e}), retrying");
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+                channel = Some(current.clone());
+                current
+            }
+        };
+
+        let message = pending.front().expect("checked above");
+        match publish(&current, message).await {
+            Ok(()) => {
+                let message = pending.pop_front().expect("checked above");
+                if let Some(confirmed) = message.confirmed {
+                    let _ = confirmed.send(());
+                }
+            }
+            Err(e) => {
+                println!(
+                    "Failed to publish to '{}'/'{}' ({e}), {} messages buffered until reconnection",
+                    message.exchange,
+                    message.routing_key,
+                    pending.len()
+                );
+                channel = None;
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn publish(channel: &Channel, message: &OutgoingMessage) -> Result<(), String> {
+    let confirmation = channel
+        .basic_publish(
+            &message.exchange,
+            &message.routing_key,
+            BasicPublishOptions::default(),
+            &message.payload,
+            message.properties.clone(),
+        )
+        .await
+        .map_err(|e| e.to_string())?
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if confirmation.is_nack() {
+        return Err("the broker nacked the message".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::AuctionFinished;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let schedule: Vec<Duration> = std::iter::successors(Some(INITIAL_BACKOFF), |b| Some(next_backoff(*b)))
+            .take(9)
+            .collect();
+        let secs: Vec<f64> = schedule.iter().map(Duration::as_secs_f64).collect();
+        assert_eq!(secs, [0.5, 1.0, 2.0, 4.0, 8.0, 16.0, 30.0, 30.0, 30.0]);
+    }
+
+    #[test]
+    fn envelope_properties_carry_ids_and_persistence() {
+        let envelope = Envelope::new(MessageType::AuctionFinished, AuctionFinished { auction_id: 1 })
+            .correlated_with("bid-1");
+        let properties = envelope_properties(&envelope);
+
+        assert_eq!(properties.delivery_mode(), &Some(2));
+        assert_eq!(properties.message_id().as_ref().map(|id| id.as_str()), Some(envelope.message_id.as_str()));
+        assert_eq!(properties.correlation_id().as_ref().map(|id| id.as_str()), Some("bid-1"));
+        assert_eq!(properties.kind().as_ref().map(|kind| kind.as_str()), Some("AuctionFinished"));
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::SystemTime;
+
+use lapin::{
+    message::Delivery,
+    types::{AMQPValue, FieldTable, LongString, ShortString},
+    BasicProperties,
+};
+
+/*==================================================== HEADERS ====================================================*/
+
+/// Why the consumer gave up on the message.
+pub const HEADER_REASON: &str = "x-failure-reason";
+/// Queue the message was consumed from; replays go back there.
+pub const HEADER_QUEUE: &str = "x-original-queue";
+pub const HEADER_EXCHANGE: &str = "x-original-exchange";
+pub const HEADER_ROUTING_KEY: &str = "x-original-routing-key";
+/// Milliseconds since the epoch.
+pub const HEADER_FAILED_AT: &str = "x-failed-at";
+/// Unique per dead-lettered copy, so messages without a `message_id` can still be picked.
+pub const HEADER_ID: &str = "x-dead-letter-id";
+
+const HEADERS: [&str; 6] = [
+    HEADER_REASON,
+    HEADER_QUEUE,
+    HEADER_EXCHANGE,
+    HEADER_ROUTING_KEY,
+    HEADER_FAILED_AT,
+    HEADER_ID,
+];
+
+/// Properties of the copy sent to `mensagens_mortas`: the original ones plus
+/// where the message came from and why it was dead-lettered.
+pub fn dead_letter_properties(delivery: &Delivery, queue: &str, reason: &str) -> BasicProperties {
+    with_failure_headers(
+        &delivery.properties,
+        delivery.exchange.as_str(),
+        delivery.routing_key.as_str(),
+        queue,
+        reason
+    )
+}
+
+fn with_failure_headers(
+    properties: &BasicProperties,
+    exchange: &str,
+    routing_key: &str,
+    queue: &str,
+    reason: &str
+) -> BasicProperties {
+    let failed_at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    insert(&mut headers, HEADER_REASON, reason.to_string());
+    insert(&mut headers, HEADER_QUEUE, queue.to_string());
+    insert(&mut headers, HEADER_EXCHANGE, exchange.to_string());
+    insert(&mut headers, HEADER_ROUTING_KEY, routing_key.to_string());
+    insert(&mut headers, HEADER_FAILED_AT, failed_at.to_string());
+    insert(&mut headers, HEADER_ID, uuid::Uuid::new_v4().to_string());
+
+    properties.clone().with_headers(headers)
+}
+
+/// Properties for replaying a dead-lettered message: the original ones, without
+/// the headers added by [`dead_letter_properties`].
+pub fn replay_properties(properties: &BasicProperties) -> BasicProperties {
+    let mut headers = FieldTable::default();
+    if let Some(original) = properties.headers() {
+        for (key, value) in original.inner() {
+            if !HEADERS.contains(&key.as_str()) {
+                headers.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    properties.clone().with_headers(headers)
+}
+
+pub fn header(properties: &BasicProperties, name: &str) -> Option<String> {
+    properties
+        .headers()
+        .as_ref()?
+        .inner()
+        .get(name)?
+        .as_long_string()
+        .map(|value| value.to_string())
+}
+
+fn insert(headers: &mut FieldTable, name: &str, value: String) {
+    headers.insert(ShortString::from(name), AMQPValue::LongString(LongString::from(value)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_headers_are_added_and_dropped_again_on_replay() {
+        let mut original_headers = FieldTable::default();
+        insert(&mut original_headers, "x-trace", "abc".to_string());
+        let original = BasicProperties::default()
+            .with_message_id(ShortString::from("m-1"))
+            .with_delivery_mode(2)
+            .with_headers(original_headers);
+
+        let dead = with_failure_headers(&original, "lance_realizado", "key", "lance_realizado", "bad json");
+        assert_eq!(header(&dead, HEADER_REASON).as_deref(), Some("bad json"));
+        assert_eq!(header(&dead, HEADER_QUEUE).as_deref(), Some("lance_realizado"));
+        assert_eq!(header(&dead, HEADER_ROUTING_KEY).as_deref(), Some("key"));
+        assert!(header(&dead, HEADER_ID).is_some());
+        assert_eq!(header(&dead, "x-trace").as_deref(), Some("abc"));
+
+        // two copies of the same message can still be told apart
+        let again = with_failure_headers(&original, "lance_realizado", "key", "lance_realizado", "bad json");
+        assert_ne!(header(&dead, HEADER_ID), header(&again, HEADER_ID));
+
+        let replayed = replay_properties(&dead);
+        assert!(HEADERS.iter().all(|name| header(&replayed, name).is_none()));
+        assert_eq!(header(&replayed, "x-trace").as_deref(), Some("abc"));
+        assert_eq!(replayed.message_id(), original.message_id());
+        assert_eq!(replayed.delivery_mode(), &Some(2));
+    }
+}
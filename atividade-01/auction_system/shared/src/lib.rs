@@ -1,10 +1,31 @@
 
 
+pub mod broker;
+pub mod config;
+pub mod dead_letter;
 pub mod models;
+pub mod rpc;
+pub mod signing;
+pub mod topology;
 
 #[cfg(test)]
 mod tests {
-    use crate::models::Auction;
+    use crate::models::{
+        Auction,
+        AuctionFinished,
+        AuctionKind,
+        AuctionOutcome,
+        Bid,
+        Currency,
+        Envelope,
+        EnvelopeError,
+        MessageType,
+        Money,
+        MoneyError,
+        Pricing,
+        RejectionReason,
+        UnitPricing,
+    };
     #[test]
     fn test_auction_creation() {
         let auction = Auction {
@@ -12,9 +33,157 @@ mod tests {
             item: "Test".to_string(),
             start_timestamp: 0,
             end_timestamp: 0,
-            status: false
+            status: false,
+            pricing: Pricing::default(),
+            soft_close_secs: None,
+            kind: Default::default(),
+            quantity: 1,
+            unit_pricing: UnitPricing::default(),
+            currency: Currency::default()
         };
         assert_eq!(auction.id, 1);
     }
-}
 
+    #[test]
+    fn test_soft_close_end() {
+        let auction = Auction::new(1, "Test".to_string(), 0, 60_000).with_soft_close(Some(30));
+
+        assert_eq!(auction.soft_close_end(10_000), None);
+        assert_eq!(auction.soft_close_end(50_000), Some(80_000));
+        assert_eq!(auction.soft_close_end(60_000), None);
+        assert_eq!(Auction::new(2, "Test".to_string(), 0, 60_000).soft_close_end(50_000), None);
+    }
+
+    fn brl(amount: &str) -> Money {
+        amount.parse().unwrap()
+    }
+
+    fn bid(client_id: u32, value: &str) -> Bid {
+        units(client_id, value, 1)
+    }
+
+    fn units(client_id: u32, value: &str, quantity: u32) -> Bid {
+        Bid { auction_id: 1, client_id, value: brl(value), signature: String::new(), public_key: String::new(), valid: true, quantity, nonce: 0, timestamp: 0, signature_scheme: Default::default() }
+    }
+
+    fn awards(outcome: Option<AuctionOutcome>) -> Vec<(u32, u32, Money)> {
+        match outcome {
+            Some(AuctionOutcome::Winners(awards)) => awards
+                .into_iter()
+                .map(|award| (award.bid.client_id, award.quantity, award.price))
+                .collect(),
+            other => panic!("unexpected outcome {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_second_price_winner_pays_the_runner_up() {
+        let auction = Auction::new(1, "Test".to_string(), 0, 100).with_kind(AuctionKind::SealedSecondPrice);
+        let (first, second, third) = (bid(1, "30"), bid(2, "50"), bid(3, "50"));
+
+        assert!(matches!(auction.check_bid(&bid(1, "60"), &[&first], 10), Err(RejectionReason::AlreadyBid)));
+        assert_eq!(awards(auction.outcome(&[&first, &second, &third])), [(2, 1, brl("50"))]);
+    }
+
+    #[test]
+    fn test_multi_unit_winners() {
+        let auction = Auction::new(1, "Test".to_string(), 0, 100).with_units(5, UnitPricing::Discriminatory);
+        let (first, second, third, raised) = (units(1, "10", 3), units(2, "12", 3), units(3, "8", 1), units(1, "11", 3));
+        let bids = [&first, &second, &third, &raised];
+
+        // client 1 only counts with its best bid, and gets what is left
+        assert_eq!(awards(auction.outcome(&bids)), [(2, 3, brl("12")), (1, 2, brl("11"))]);
+        let uniform = auction.clone().with_units(5, UnitPricing::Uniform);
+        assert_eq!(awards(uniform.outcome(&bids)), [(2, 3, brl("11")), (1, 2, brl("11"))]);
+
+        // all units are taken, so a new bid has to beat the lowest winning one
+        assert!(matches!(auction.check_bid(&units(3, "11", 1), &bids, 10), Err(RejectionReason::ValueNotHigher { .. })));
+        assert!(auction.check_bid(&units(3, "11.5", 1), &bids, 10).is_ok());
+        assert!(auction.check_bid(&units(4, "1", 1), &[&first], 10).is_ok());
+        assert!(matches!(auction.check_bid(&units(4, "20", 6), &[], 10), Err(RejectionReason::InvalidQuantity { available: 5 })));
+    }
+
+    #[test]
+    fn test_dutch_price_falls_to_the_floor() {
+        let pricing = Pricing { starting_price: Some(brl("100")), reserve_price: Some(brl("20")), ..Pricing::default() };
+        let auction = Auction::new(1, "Test".to_string(), 1_000, 2_000)
+            .with_pricing(pricing)
+            .with_kind(AuctionKind::Dutch);
+
+        assert_eq!(auction.dutch_price(0), Some(brl("100")));
+        assert_eq!(auction.dutch_price(1_500), Some(brl("60")));
+        assert_eq!(auction.dutch_price(5_000), Some(brl("20")));
+        assert!(matches!(auction.check_bid(&bid(1, "50"), &[], 1_500), Err(RejectionReason::BelowCurrentPrice { .. })));
+        assert!(auction.check_bid(&bid(1, "60"), &[], 1_500).is_ok());
+        assert!(matches!(auction.check_bid(&bid(2, "90"), &[&bid(1, "60")], 1_500), Err(RejectionReason::AuctionClosed)));
+    }
+
+    #[test]
+    fn test_pricing_rules() {
+        let pricing = Pricing { starting_price: Some(brl("10")), min_increment: Some(brl("2")), reserve_price: Some(brl("50")) };
+
+        assert!(matches!(pricing.check_bid(brl("5"), None), Err(RejectionReason::BelowStartingPrice { .. })));
+        assert!(pricing.check_bid(brl("10"), None).is_ok());
+        assert!(matches!(pricing.check_bid(brl("11"), Some(brl("10"))), Err(RejectionReason::IncrementTooSmall { .. })));
+        assert!(pricing.check_bid(brl("12"), Some(brl("10"))).is_ok());
+    }
+
+    #[test]
+    fn test_money_parsing_and_serialization() {
+        assert_eq!(brl("12.5"), Money::new(1250, Currency::Brl));
+        assert_eq!(Money::parse("3 usd", Currency::Brl), Ok(Money::new(300, Currency::Usd)));
+        assert_eq!(Money::parse("0.10EUR", Currency::Brl).unwrap().to_string(), "0.10 EUR");
+        assert!(matches!("NaN".parse::<Money>(), Err(MoneyError::NotANumber(_))));
+        assert!(matches!("1e3".parse::<Money>(), Err(MoneyError::NotANumber(_))));
+        assert_eq!("-1".parse::<Money>(), Err(MoneyError::Negative));
+        assert_eq!("0.001".parse::<Money>(), Err(MoneyError::TooPrecise));
+        assert!(matches!("1 XYZ".parse::<Money>(), Err(MoneyError::UnknownCurrency(_))));
+
+        assert_eq!(serde_json::to_string(&brl("7.05")).unwrap(), r#""7.05 BRL""#);
+        assert_eq!(serde_json::from_str::<Money>(r#""7.05 BRL""#).unwrap(), brl("7.05"));
+        // amounts stored as floats before
+        assert_eq!(serde_json::from_str::<Money>("12.5").unwrap(), brl("12.50"));
+        assert!(serde_json::from_str::<Money>("-1.0").is_err());
+
+        let dollars = Bid { value: Money::new(100, Currency::Usd), ..bid(1, "1") };
+        let auction = Auction::new(1, "Test".to_string(), 0, 100);
+        assert_eq!(auction.check_bid(&dollars, &[], 10), Err(RejectionReason::WrongCurrency { expected: Currency::Brl }));
+    }
+
+    #[test]
+    fn test_signed_bid_payload() {
+        let signed = Bid { nonce: 42, timestamp: 1_700_000_000_000, ..units(3, "12.5", 2) };
+
+        assert_eq!(
+            String::from_utf8(signed.signed_payload()).unwrap(),
+            r#"{"purpose":"bid/1","auction_id":1,"client_id":3,"value":"12.50 BRL","quantity":2,"nonce":42,"timestamp":1700000000000}"#
+        );
+        assert_ne!(signed.signed_payload(), Bid { nonce: 43, ..signed.clone() }.signed_payload());
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let envelope = Envelope::new(MessageType::AuctionFinished, AuctionFinished { auction_id: 7 })
+            .correlated_with("previous");
+        let data = envelope.encode().unwrap();
+
+        let decoded: Envelope<AuctionFinished> = Envelope::decode(&data, MessageType::AuctionFinished).unwrap();
+        assert_eq!(decoded.payload, AuctionFinished { auction_id: 7 });
+        assert_eq!(decoded.message_id, envelope.message_id);
+        assert_eq!(decoded.correlation_id.as_deref(), Some("previous"));
+
+        let wrong_type = Envelope::<AuctionFinished>::decode(&data, MessageType::BidPlaced);
+        assert!(matches!(wrong_type, Err(EnvelopeError::UnexpectedType { .. })));
+    }
+
+    #[test]
+    fn test_envelope_rejects_unknown_version() {
+        let data = br#"{"version":99,"message_type":"SomethingNew","payload":{}}"#;
+        let decoded = Envelope::<AuctionFinished>::decode(data, MessageType::AuctionFinished);
+        assert!(matches!(decoded, Err(EnvelopeError::UnsupportedVersion(99))));
+
+        let legacy = 7u32.to_ne_bytes();
+        let decoded = Envelope::<AuctionFinished>::decode(&legacy, MessageType::AuctionFinished);
+        assert!(matches!(decoded, Err(EnvelopeError::Malformed(_))));
+    }
+}
@@ -1,4 +1,8 @@
-use serde::{Serialize, Deserialize};
+use std::{collections::HashMap, fmt, str::FromStr, time::SystemTime};
+
+use serde::{de::{self, DeserializeOwned}, Deserializer, Serialize, Serializer, Deserialize};
+
+use crate::signing::SignatureScheme;
 
 
 /* ========================================= AUCITON ========================================= */
@@ -9,7 +13,27 @@ pub struct Auction {
     pub item: String,
     pub start_timestamp: u128,
     pub end_timestamp: u128,
-    pub status: bool
+    pub status: bool,
+    #[serde(default)]
+    pub pricing: Pricing,
+    /// Anti-sniping window: a bid accepted this close to the end pushes the end
+    /// to this many seconds after the bid.
+    #[serde(default)]
+    pub soft_close_secs: Option<u64>,
+    #[serde(default)]
+    pub kind: AuctionKind,
+    /// Identical units sold in this auction, each bid asks for some of them.
+    #[serde(default = "one_unit")]
+    pub quantity: u32,
+    #[serde(default)]
+    pub unit_pricing: UnitPricing,
+    /// Bids in any other currency are refused.
+    #[serde(default)]
+    pub currency: Currency
+}
+
+fn one_unit() -> u32 {
+    1
 }
 
 impl Auction {
@@ -24,23 +48,559 @@ impl Auction {
             item,
             start_timestamp,
             end_timestamp,
-            status: true
+            status: true,
+            pricing: Pricing::default(),
+            soft_close_secs: None,
+            kind: AuctionKind::default(),
+            quantity: 1,
+            unit_pricing: UnitPricing::default(),
+            currency: Currency::default()
+        }
+    }
+
+    pub fn with_pricing(mut self, pricing: Pricing) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    pub fn with_soft_close(mut self, soft_close_secs: Option<u64>) -> Self {
+        self.soft_close_secs = soft_close_secs;
+        self
+    }
+
+    pub fn with_kind(mut self, kind: AuctionKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn with_units(mut self, quantity: u32, unit_pricing: UnitPricing) -> Self {
+        self.quantity = quantity;
+        self.unit_pricing = unit_pricing;
+        self
+    }
+
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    /// Asking price of a Dutch auction at `now`: falls linearly from the starting
+    /// price at the start to the reserve price, or zero, at the end.
+    /// Rounded up to the cent.
+    pub fn dutch_price(&self, now: u128) -> Option<Money> {
+        if self.kind != AuctionKind::Dutch {
+            return None;
+        }
+
+        let starting_price = self.pricing.starting_price?;
+        let floor = self.pricing.reserve_price.unwrap_or(Money::new(0, starting_price.currency()));
+        let duration = self.end_timestamp.saturating_sub(self.start_timestamp);
+        if duration == 0 {
+            return Some(floor);
+        }
+
+        let elapsed = now.clamp(self.start_timestamp, self.end_timestamp) - self.start_timestamp;
+        let span = starting_price.cents().saturating_sub(floor.cents()) as u128;
+        let dropped = (span * elapsed / duration) as u64;
+        Some(Money::new(starting_price.cents() - dropped, starting_price.currency()))
+    }
+
+    /// Checks a bid against the rules of the auction and the bids it already took.
+    pub fn check_bid(&self, bid: &Bid, bids: &[&Bid], now: u128) -> Result<(), RejectionReason> {
+        if bid.quantity == 0 || bid.quantity > self.quantity {
+            return Err(RejectionReason::InvalidQuantity { available: self.quantity });
+        }
+        if bid.value.currency() != self.currency {
+            return Err(RejectionReason::WrongCurrency { expected: self.currency });
+        }
+
+        match self.kind {
+            AuctionKind::English => {
+                // once every unit is taken, a new bid has to beat the lowest winning one
+                let (awards, _) = self.allocate(&ranked_bids(bids), None);
+                let allocated: u32 = awards.iter().map(|(_, quantity)| quantity).sum();
+                let threshold = awards
+                    .last()
+                    .filter(|_| allocated == self.quantity)
+                    .map(|(lowest, _)| lowest.value);
+                self.pricing.check_bid(bid.value, threshold)
+            }
+            AuctionKind::SealedFirstPrice | AuctionKind::SealedSecondPrice => {
+                if bids.iter().any(|b| b.client_id == bid.client_id) {
+                    return Err(RejectionReason::AlreadyBid);
+                }
+                self.pricing.check_bid(bid.value, None)
+            }
+            AuctionKind::Dutch => {
+                // the first bid takes the item
+                if !bids.is_empty() {
+                    return Err(RejectionReason::AuctionClosed);
+                }
+                let current_price = self.dutch_price(now).unwrap_or(Money::new(0, self.currency));
+                if bid.value < current_price {
+                    return Err(RejectionReason::BelowCurrentPrice { current_price });
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Who won the auction, how many units each and what they pay per unit, given
+    /// the bids it took in order. `None` when nobody bid.
+    pub fn outcome(&self, bids: &[&Bid]) -> Option<AuctionOutcome> {
+        let ranked = ranked_bids(bids);
+        let highest = *ranked.first()?;
+
+        // in a Dutch auction the reserve is the floor of the price, already enforced
+        let floor = match self.kind {
+            AuctionKind::Dutch => None,
+            _ => self.pricing.reserve_price,
+        };
+        let (awards, highest_losing) = self.allocate(&ranked, floor);
+        let Some((lowest_winning, _)) = awards.last() else {
+            return Some(AuctionOutcome::ReserveNotMet {
+                highest_bid: highest.clone(),
+                reserve_price: floor.unwrap_or(Money::new(0, self.currency))
+            });
+        };
+
+        // without a single price, every winner pays what it bid
+        let single_price = match (self.kind, self.unit_pricing) {
+            (AuctionKind::SealedSecondPrice, _) => highest_losing
+                .into_iter()
+                .chain(self.pricing.reserve_price)
+                .chain(self.pricing.starting_price)
+                .max(),
+            (_, UnitPricing::Uniform) => Some(lowest_winning.value),
+            (_, UnitPricing::Discriminatory) => None,
+        };
+
+        Some(AuctionOutcome::Winners(
+            awards
+                .iter()
+                .map(|(bid, quantity)| Award {
+                    bid: (*bid).clone(),
+                    quantity: *quantity,
+                    price: single_price.unwrap_or(bid.value)
+                })
+                .collect()
+        ))
+    }
+
+    /// Hands the units out to the ranked bids at or above `floor`, highest first.
+    /// The last winner may get fewer units than it asked for. Also returns the
+    /// value of the highest bid that got nothing.
+    fn allocate<'a>(&self, ranked: &[&'a Bid], floor: Option<Money>) -> (Vec<(&'a Bid, u32)>, Option<Money>) {
+        let mut remaining = self.quantity;
+        let mut awards = Vec::new();
+        let mut highest_losing = None;
+
+        for bid in ranked {
+            if remaining == 0 || floor.is_some_and(|floor| bid.value < floor) {
+                highest_losing.get_or_insert(bid.value);
+                continue;
+            }
+
+            let quantity = bid.quantity.min(remaining);
+            remaining -= quantity;
+            awards.push((*bid, quantity));
+        }
+
+        (awards, highest_losing)
+    }
+
+    /// The end a bid placed at `bid_timestamp` moves the auction to, if it falls
+    /// within the soft-close window.
+    pub fn soft_close_end(&self, bid_timestamp: u128) -> Option<u128> {
+        let window = self.soft_close_secs? as u128 * 1000;
+        let new_end = bid_timestamp + window;
+        (bid_timestamp < self.end_timestamp && new_end > self.end_timestamp).then_some(new_end)
+    }
+}
+
+/// The best bid of each client, highest first. Equal bids keep the order they came in.
+fn ranked_bids<'a>(bids: &[&'a Bid]) -> Vec<&'a Bid> {
+    let mut best: HashMap<u32, (usize, &'a Bid)> = HashMap::new();
+    for (order, bid) in bids.iter().enumerate() {
+        let entry = best.entry(bid.client_id).or_insert((order, bid));
+        if bid.value > entry.1.value {
+            *entry = (order, bid);
+        }
+    }
+
+    let mut ranked: Vec<(usize, &Bid)> = best.into_values().collect();
+    ranked.sort_by(|(order_a, a), (order_b, b)| {
+        b.value.cmp(&a.value).then(order_a.cmp(order_b))
+    });
+    ranked.into_iter().map(|(_, bid)| bid).collect()
+}
+
+/// What the winners of a multi-unit auction pay per unit.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum UnitPricing {
+    /// Every winner pays its own bid.
+    #[default]
+    Discriminatory,
+    /// Every winner pays the lowest winning bid.
+    Uniform,
+}
+
+/// How an auction takes bids and picks its winner.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum AuctionKind {
+    /// Open ascending auction, every bid has to beat the current highest one.
+    #[default]
+    English,
+    /// One secret bid per client, the highest pays what it bid.
+    SealedFirstPrice,
+    /// One secret bid per client, the highest pays the second highest bid (Vickrey).
+    SealedSecondPrice,
+    /// Descending price, the first bid at or above the asking price wins.
+    Dutch,
+}
+
+impl AuctionKind {
+    /// Bids of sealed auctions are only revealed once they close.
+    pub fn is_sealed(&self) -> bool {
+        matches!(self, AuctionKind::SealedFirstPrice | AuctionKind::SealedSecondPrice)
+    }
+}
+
+/// Optional bidding rules of an auction. Without them any higher bid is taken
+/// and the highest one always wins.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Pricing {
+    #[serde(default)]
+    pub starting_price: Option<Money>,
+    #[serde(default)]
+    pub min_increment: Option<Money>,
+    #[serde(default)]
+    pub reserve_price: Option<Money>
+}
+
+impl Pricing {
+    /// Checks a bid against the current highest bid of the auction, if any.
+    pub fn check_bid(&self, value: Money, highest: Option<Money>) -> Result<(), RejectionReason> {
+        let Some(current_max) = highest else {
+            return match self.starting_price {
+                Some(starting_price) if value < starting_price => Err(RejectionReason::BelowStartingPrice { starting_price }),
+                _ => Ok(()),
+            };
+        };
+
+        if value <= current_max {
+            return Err(RejectionReason::ValueNotHigher { current_max });
+        }
+        if let Some(minimum) = self.min_increment.and_then(|min_increment| current_max.checked_add(min_increment))
+            && value < minimum {
+            return Err(RejectionReason::IncrementTooSmall { minimum });
+        }
+
+        Ok(())
+    }
+}
+
+/* ========================================= MONEY ========================================= */
+
+/// Currencies amounts can be in, all of them with cents.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Currency {
+    #[default]
+    #[serde(rename = "BRL")]
+    Brl,
+    #[serde(rename = "USD")]
+    Usd,
+    #[serde(rename = "EUR")]
+    Eur,
+}
+
+impl Currency {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Currency::Brl => "BRL",
+            Currency::Usd => "USD",
+            Currency::Eur => "EUR",
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl FromStr for Currency {
+    type Err = MoneyError;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        [Currency::Brl, Currency::Usd, Currency::Eur]
+            .into_iter()
+            .find(|currency| currency.code().eq_ignore_ascii_case(code))
+            .ok_or_else(|| MoneyError::UnknownCurrency(code.to_string()))
+    }
+}
+
+/// An amount of money, held as a whole number of cents so that comparing two
+/// bids never panics and every service formats, and signs, the same value the
+/// same way: `12.50 BRL`.
+///
+/// Amounts in different currencies are ordered by currency first; auctions refuse
+/// bids in another currency, so those never meet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money {
+    currency: Currency,
+    cents: u64
+}
+
+impl Money {
+    pub const fn new(cents: u64, currency: Currency) -> Money {
+        Money { currency, cents }
+    }
+
+    pub fn cents(&self) -> u64 {
+        self.cents
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    /// `None` on overflow or when the currencies differ.
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(Money::new(self.cents.checked_add(other.cents)?, self.currency))
+    }
+
+    /// Parses `12`, `12.5`, `12.50 USD` or `12.50USD`. Amounts without a currency
+    /// are in `currency`. Rejects anything that is not a plain non-negative decimal
+    /// with at most two decimal places, such as `NaN`, `-1`, `1e3` or `0.001`.
+    pub fn parse(text: &str, currency: Currency) -> Result<Money, MoneyError> {
+        let text = text.trim();
+        let amount_len = text.trim_end_matches(|c: char| c.is_ascii_alphabetic()).len();
+        let (amount, code) = text.split_at(amount_len);
+        let amount = amount.trim_end();
+        if amount.is_empty() {
+            return Err(MoneyError::NotANumber(text.to_string()));
+        }
+        let currency = if code.is_empty() { currency } else { code.parse()? };
+        if amount.starts_with('-') {
+            return Err(MoneyError::Negative);
+        }
+
+        let (units, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+        let is_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+        if units.is_empty() || !is_digits(units) || !is_digits(fraction) {
+            return Err(MoneyError::NotANumber(text.to_string()));
+        }
+        if fraction.len() > 2 {
+            return Err(MoneyError::TooPrecise);
         }
+
+        let units: u64 = units.parse().map_err(|_| MoneyError::TooLarge)?;
+        let fraction: u64 = format!("{fraction:0<2}").parse().unwrap();
+        let cents = units
+            .checked_mul(100)
+            .and_then(|cents| cents.checked_add(fraction))
+            .ok_or(MoneyError::TooLarge)?;
+
+        Ok(Money::new(cents, currency))
+    }
+
+    /// Amounts were plain floats before, in the default currency.
+    fn from_legacy(value: f64) -> Option<Money> {
+        let cents = (value * 100.0).round();
+        (cents.is_finite() && cents >= 0.0 && cents < u64::MAX as f64)
+            .then(|| Money::new(cents as u64, Currency::default()))
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:02} {}", self.cents / 100, self.cents % 100, self.currency)
+    }
+}
+
+impl FromStr for Money {
+    type Err = MoneyError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Money::parse(text, Currency::default())
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
     }
 }
 
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Canonical(String),
+            Legacy(f64),
+        }
+
+        match Stored::deserialize(deserializer)? {
+            Stored::Canonical(text) => text.parse().map_err(de::Error::custom),
+            Stored::Legacy(value) => Money::from_legacy(value)
+                .ok_or_else(|| de::Error::custom(format!("invalid amount {value}"))),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MoneyError {
+    NotANumber(String),
+    Negative,
+    TooPrecise,
+    TooLarge,
+    UnknownCurrency(String),
+}
+
+impl fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoneyError::NotANumber(text) => write!(f, "'{text}' is not an amount, use e.g. 12.50 or 12.50 USD"),
+            MoneyError::Negative => write!(f, "amounts cannot be negative"),
+            MoneyError::TooPrecise => write!(f, "amounts have at most two decimal places"),
+            MoneyError::TooLarge => write!(f, "amount is too large"),
+            MoneyError::UnknownCurrency(code) => write!(f, "unknown currency '{code}', use BRL, USD or EUR"),
+        }
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
 /* ========================================= BID ========================================= */
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Bid {
     pub auction_id: u32,
     pub client_id: u32,
-    pub value: f64,
+    pub value: Money,
     pub signature: String,
     pub public_key: String,
-    pub valid: bool
+    pub valid: bool,
+    /// Units asked for, `value` is the price of each.
+    #[serde(default = "one_unit")]
+    pub quantity: u32,
+    /// Picked at random by the client for this bid only, so a copy of it can be told apart.
+    #[serde(default)]
+    pub nonce: u64,
+    /// When the client signed the bid, in epoch milliseconds.
+    #[serde(default)]
+    pub timestamp: u128,
+    /// How `signature` was made.
+    #[serde(default)]
+    pub signature_scheme: SignatureScheme
+}
+
+impl Bid {
+    /// The bytes the client signs and bid-srv verifies: every field bid-srv acts
+    /// on, as JSON in a fixed field order.
+    pub fn signed_payload(&self) -> Vec<u8> {
+        let payload = SignedBid {
+            purpose: "bid/1",
+            auction_id: self.auction_id,
+            client_id: self.client_id,
+            value: self.value,
+            quantity: self.quantity,
+            nonce: self.nonce,
+            timestamp: self.timestamp
+        };
+        serde_json::to_vec(&payload).expect("a bid always serializes")
+    }
+}
+
+/// What a bid signature covers. `purpose` keeps it from being valid for anything
+/// else signed with the same key.
+#[derive(Serialize)]
+struct SignedBid {
+    purpose: &'static str,
+    auction_id: u32,
+    client_id: u32,
+    value: Money,
+    quantity: u32,
+    nonce: u64,
+    timestamp: u128
+}
+
+/// Payload of `lance_validado`. Carries the kind of the auction so notification-srv
+/// can keep the bids of sealed auctions secret.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ValidatedBid{
+    pub bid: Bid,
+    pub kind: AuctionKind
+}
+
+/// Why bid-srv refused a bid.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RejectionReason{
+    AuctionNotFound,
+    AuctionClosed,
+    ValueNotHigher { current_max: Money },
+    BelowStartingPrice { starting_price: Money },
+    IncrementTooSmall { minimum: Money },
+    BelowCurrentPrice { current_price: Money },
+    AlreadyBid,
+    InvalidQuantity { available: u32 },
+    WrongCurrency { expected: Currency },
+    BadSignature,
+    /// Signed too long ago, or too far in the future.
+    StaleBid,
+    /// Same nonce as an earlier bid of the client.
+    ReplayedBid,
+    UnknownClientKey,
+    /// The client revoked its key and has not registered another.
+    RevokedClientKey
 }
 
+impl fmt::Display for RejectionReason{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self {
+            RejectionReason::AuctionNotFound => write!(f, "auction not found"),
+            RejectionReason::AuctionClosed => write!(f, "auction is closed"),
+            RejectionReason::ValueNotHigher { current_max } => write!(f, "value is not higher than the current max of {current_max}"),
+            RejectionReason::BelowStartingPrice { starting_price } => write!(f, "value is below the starting price of {starting_price}"),
+            RejectionReason::IncrementTooSmall { minimum } => write!(f, "value is below the minimum next bid of {minimum}"),
+            RejectionReason::BelowCurrentPrice { current_price } => write!(f, "value is below the current price of {current_price}"),
+            RejectionReason::AlreadyBid => write!(f, "only one bid per client is allowed in a sealed auction"),
+            RejectionReason::InvalidQuantity { available } => write!(f, "quantity must be between 1 and the {available} units on sale"),
+            RejectionReason::WrongCurrency { expected } => write!(f, "bids on this auction must be in {expected}"),
+            RejectionReason::BadSignature => write!(f, "bad signature"),
+            RejectionReason::StaleBid => write!(f, "bid was signed too long ago, check the clock of the client"),
+            RejectionReason::ReplayedBid => write!(f, "bid was already submitted"),
+            RejectionReason::UnknownClientKey => write!(f, "no public key registered for this client"),
+            RejectionReason::RevokedClientKey => write!(f, "the key of this client was revoked"),
+        }
+    }
+}
+
+/// Payload of `lance_rejeitado`, correlated with the message of the refused bid.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BidRejection{
+    pub auction_id: u32,
+    pub client_id: u32,
+    pub value: Money,
+    pub reason: RejectionReason
+}
+
+impl BidRejection{
+    pub fn new(bid: &Bid, reason: RejectionReason) -> Self{
+        BidRejection {
+            auction_id: bid.auction_id,
+            client_id: bid.client_id,
+            value: bid.value,
+            reason
+        }
+    }
+}
 
 /* ========================================= NOTIFICATION ========================================= */
 
@@ -57,36 +617,408 @@ impl Notification{
             data: NotificationData { 
                 auction_id: bid.auction_id, 
                 client_id: bid.client_id, 
-                bid_value: bid.value 
+                bid_value: bid.value,
+                end_timestamp: None,
+                quantity: Some(bid.quantity)
             } 
         }
     }
 
+    /// One of the winners of an auction, with the units it got and the price it
+    /// pays for each instead of its bid.
+    pub fn auction_won(award: &Award) -> Notification{
+        let mut notification = Notification::from_bid(&award.bid, NotificationType::AuctionWinner);
+        notification.data.bid_value = award.price;
+        notification.data.quantity = Some(award.quantity);
+        notification
+    }
+
+    pub fn auction_cancelled(auction_id: u32) -> Notification{
+        Notification {
+            notification_type: NotificationType::AuctionCancelled,
+            data: NotificationData {
+                auction_id,
+                client_id: 0,
+                bid_value: Money::default(),
+                end_timestamp: None,
+                quantity: None
+            }
+        }
+    }
+
+    pub fn auction_extended(auction_id: u32, end_timestamp: u128) -> Notification{
+        Notification {
+            notification_type: NotificationType::AuctionExtended,
+            data: NotificationData {
+                auction_id,
+                client_id: 0,
+                bid_value: Money::default(),
+                end_timestamp: Some(end_timestamp),
+                quantity: None
+            }
+        }
+    }
+
     pub fn get_auction_id(&self) -> u32{
-        return self.data.auction_id;
+        self.data.auction_id
     }
 
     pub fn get_client_id(&self) -> u32{
-        return self.data.client_id;
+        self.data.client_id
     }
     
-    pub fn get_bid_value(&self) -> f64{
-        return self.data.bid_value;
+    pub fn get_bid_value(&self) -> Money{
+        self.data.bid_value
+    }
+
+    /// Only set on `AuctionExtended`.
+    pub fn get_end_timestamp(&self) -> Option<u128>{
+        self.data.end_timestamp
+    }
+
+    /// Units bid for or won, 1 for notifications published before multi-unit auctions.
+    pub fn get_quantity(&self) -> u32{
+        self.data.quantity.unwrap_or(1)
     }
 
     pub fn get_notification_type(&self) ->NotificationType{
-        return self.notification_type.clone();
+        self.notification_type.clone()
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum NotificationType{
     NewBid,
-    AuctionWinner
+    AuctionWinner,
+    ReserveNotMet,
+    AuctionCancelled,
+    AuctionExtended
 }
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct NotificationData{
     auction_id: u32,
     client_id: u32,
-    bid_value: f64,
-}
\ No newline at end of file
+    bid_value: Money,
+    #[serde(default)]
+    end_timestamp: Option<u128>,
+    #[serde(default)]
+    quantity: Option<u32>,
+}
+
+/* ========================================= QUERIES ========================================= */
+
+/// Request sent to `consulta_leiloes` (auction-srv), `consulta_lances` or `registro_chaves` (bid-srv).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Query{
+    ListAuctions,
+    GetAuction { auction_id: u32 },
+    GetHighestBid { auction_id: u32 },
+    GetBidHistory { auction_id: u32 },
+    Key(KeyRequest)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuctionStatus{
+    Scheduled,
+    Open,
+    Closed,
+    Cancelled
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuctionSummary{
+    pub auction: Auction,
+    pub status: AuctionStatus
+}
+
+/// A bid as other clients get to see it, without the signature.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BidSummary{
+    pub client_id: u32,
+    pub value: Money,
+    #[serde(default = "one_unit")]
+    pub quantity: u32
+}
+
+impl From<&Bid> for BidSummary{
+    fn from(bid: &Bid) -> Self{
+        BidSummary { client_id: bid.client_id, value: bid.value, quantity: bid.quantity }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum QueryReply{
+    Auctions(Vec<AuctionSummary>),
+    Auction(Option<AuctionSummary>),
+    HighestBid(Option<BidSummary>),
+    BidHistory(Vec<BidSummary>),
+    Key(Result<(), KeyRejection>),
+    /// The service does not answer this kind of query.
+    Unsupported
+}
+
+/* ========================================= KEY REGISTRY ========================================= */
+
+/// Asks bid-srv to change the public key it checks the bids of a client with.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct KeyRequest{
+    pub client_id: u32,
+    pub operation: KeyOperation,
+    pub nonce: u64,
+    pub timestamp: u128,
+    /// By the key registered for the client. `Register` has none.
+    #[serde(default)]
+    pub signature: Option<KeySignature>,
+    /// By the new key of `Register` and `Rotate`, so nobody registers a key they do not hold.
+    #[serde(default)]
+    pub proof: Option<KeySignature>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum KeyOperation{
+    /// First key of a client id nobody has used yet.
+    Register { public_key: String },
+    Rotate { public_key: String },
+    /// Bids of the client are refused from now on. The id cannot be registered again.
+    Revoke
+}
+
+/// Base64 signature of [`KeyRequest::signed_payload`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct KeySignature{
+    pub scheme: SignatureScheme,
+    pub signature: String
+}
+
+impl KeyRequest{
+    pub fn new(client_id: u32, operation: KeyOperation) -> Self{
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        KeyRequest {
+            client_id,
+            operation,
+            nonce: rand::random(),
+            timestamp,
+            signature: None,
+            proof: None
+        }
+    }
+
+    /// The new key of `Register` and `Rotate`, as an OpenSSH line or a PEM.
+    pub fn new_key(&self) -> Option<&str>{
+        match &self.operation {
+            KeyOperation::Register { public_key } | KeyOperation::Rotate { public_key } => Some(public_key),
+            KeyOperation::Revoke => None
+        }
+    }
+
+    /// The bytes both signatures cover, everything but the signatures themselves.
+    pub fn signed_payload(&self) -> Vec<u8>{
+        let payload = SignedKeyRequest {
+            purpose: "key/1",
+            client_id: self.client_id,
+            operation: &self.operation,
+            nonce: self.nonce,
+            timestamp: self.timestamp
+        };
+        serde_json::to_vec(&payload).expect("a key request always serializes")
+    }
+}
+
+#[derive(Serialize)]
+struct SignedKeyRequest<'a>{
+    purpose: &'static str,
+    client_id: u32,
+    operation: &'a KeyOperation,
+    nonce: u64,
+    timestamp: u128
+}
+
+/// Why bid-srv refused a [`KeyRequest`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum KeyRejection{
+    AlreadyRegistered,
+    UnknownClient,
+    Revoked,
+    InvalidKey(String),
+    /// Missing or wrong signature by the registered key.
+    BadSignature,
+    /// Missing or wrong signature by the new key.
+    BadProof,
+    StaleRequest,
+    ReplayedRequest,
+    /// bid-srv could not save the change, nothing was changed.
+    Storage(String)
+}
+
+impl fmt::Display for KeyRejection{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self {
+            KeyRejection::AlreadyRegistered => write!(f, "a key is already registered for this client"),
+            KeyRejection::UnknownClient => write!(f, "no key registered for this client"),
+            KeyRejection::Revoked => write!(f, "the key of this client was revoked"),
+            KeyRejection::InvalidKey(e) => write!(f, "{e}"),
+            KeyRejection::BadSignature => write!(f, "not signed by the registered key"),
+            KeyRejection::BadProof => write!(f, "not signed by the new key"),
+            KeyRejection::StaleRequest => write!(f, "request was signed too long ago, check the clock of the client"),
+            KeyRejection::ReplayedRequest => write!(f, "request was already submitted"),
+            KeyRejection::Storage(e) => write!(f, "bid-srv could not save the key: {e}"),
+        }
+    }
+}
+
+/* ========================================= ENVELOPE ========================================= */
+
+/// Version of the envelope layout and of the payloads it carries. Bump it on any
+/// change that older services could not read.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType{
+    AuctionStarted,
+    AuctionFinished,
+    AuctionCancelled,
+    AuctionExtended,
+    ExtensionRequested,
+    CloseRequested,
+    BidPlaced,
+    BidValidated,
+    BidRejected,
+    Query,
+    QueryReply,
+    AuctionOutcome,
+    Notification
+}
+
+/// Payload of `leilao_vencedor`, correlated with the `leilao_finalizado` message.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum AuctionOutcome{
+    Winners(Vec<Award>),
+    /// The highest bid did not reach the reserve price, so nobody wins.
+    ReserveNotMet{
+        highest_bid: Bid,
+        reserve_price: Money
+    }
+}
+
+/// Units won by a bid. `price` is paid per unit, and can be below the bid with
+/// uniform or second-price pricing.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Award{
+    pub bid: Bid,
+    pub quantity: u32,
+    pub price: Money
+}
+
+/// Payload of `leilao_finalizado`, and of `encerramento_solicitado` when bid-srv
+/// asks for an auction to end early.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuctionFinished{
+    pub auction_id: u32
+}
+
+/// Payload of `leilao_cancelado`. A cancelled auction never finishes and has no winner.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuctionCancelled{
+    pub auction_id: u32
+}
+
+/// Payload of `leilao_prorrogado`, the new end of an auction that is already open.
+/// Also the payload of `prorrogacao_solicitada`, the end bid-srv asks for after a
+/// bid in the soft-close window.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuctionExtended{
+    pub auction_id: u32,
+    pub end_timestamp: u128
+}
+
+/// Wrapper around every message exchanged between the services.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Envelope<T>{
+    pub version: u32,
+    pub message_type: MessageType,
+    pub message_id: String,
+    /// Milliseconds since the epoch, like the auction timestamps.
+    pub timestamp: u128,
+    /// Id of the message that caused this one, if any.
+    pub correlation_id: Option<String>,
+    pub payload: T
+}
+
+impl<T> Envelope<T>{
+    pub fn new(message_type: MessageType, payload: T) -> Self{
+        Envelope {
+            version: SCHEMA_VERSION,
+            message_type,
+            message_id: uuid::Uuid::new_v4().to_string(),
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+            correlation_id: None,
+            payload
+        }
+    }
+
+    /// Replaces the random id with a deterministic one, so consumers can drop
+    /// the copies a retry may produce.
+    pub fn with_message_id(mut self, message_id: String) -> Self{
+        self.message_id = message_id;
+        self
+    }
+
+    pub fn correlated_with(mut self, message_id: &str) -> Self{
+        self.correlation_id = Some(message_id.to_string());
+        self
+    }
+}
+
+impl<T: Serialize> Envelope<T>{
+    pub fn encode(&self) -> Result<Vec<u8>, serde_json::Error>{
+        serde_json::to_vec(self)
+    }
+}
+
+impl<T: DeserializeOwned> Envelope<T>{
+    /// Parses a message, checking the version before anything else so a newer
+    /// payload layout is reported as such instead of as garbage.
+    pub fn decode(data: &[u8], expected: MessageType) -> Result<Self, EnvelopeError>{
+        #[derive(Deserialize)]
+        struct Header{
+            version: u32
+        }
+
+        let header: Header = serde_json::from_slice(data).map_err(EnvelopeError::Malformed)?;
+        if header.version != SCHEMA_VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(header.version));
+        }
+
+        let envelope: Envelope<T> = serde_json::from_slice(data).map_err(EnvelopeError::Malformed)?;
+        if envelope.message_type != expected {
+            return Err(EnvelopeError::UnexpectedType { expected, found: envelope.message_type });
+        }
+
+        Ok(envelope)
+    }
+}
+
+#[derive(Debug)]
+pub enum EnvelopeError{
+    Malformed(serde_json::Error),
+    UnsupportedVersion(u32),
+    UnexpectedType { expected: MessageType, found: MessageType }
+}
+
+impl fmt::Display for EnvelopeError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self {
+            EnvelopeError::Malformed(e) => write!(f, "malformed message: {e}"),
+            EnvelopeError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported schema version {version}, this build understands {SCHEMA_VERSION}"
+            ),
+            EnvelopeError::UnexpectedType { expected, found } => write!(f, "expected a {expected:?} message, got {found:?}"),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
@@ -0,0 +1,212 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use lapin::{options::BasicAckOptions, types::ShortString};
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::{
+    broker::{envelope_properties, Broker, Publisher},
+    models::{Envelope, MessageType, Query, QueryReply},
+};
+
+/// How long a caller waits for a reply. Requests also expire on the broker after
+/// this long, so a service that was down does not answer questions nobody waits for.
+pub(crate) const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+
+type PendingReplies = Arc<Mutex<HashMap<String, oneshot::Sender<QueryReply>>>>;
+
+/*==================================================== CLIENT ====================================================*/
+
+/// Sends [`Query`]s and matches the replies, which all arrive on `reply_queue`,
+/// to the callers through the correlation id.
+pub struct RpcClient {
+    publisher: Publisher,
+    reply_queue: String,
+    pending: PendingReplies,
+}
+
+impl RpcClient {
+    /// `reply_queue` must already exist, usually declared in an `on_connect` hook.
+    pub fn new(broker: &Arc<Broker>, reply_queue: &str) -> Self {
+        let pending: PendingReplies = Arc::new(Mutex::new(HashMap::new()));
+        tokio::spawn(task_dispatch_replies(broker.clone(), reply_queue.to_string(), pending.clone()));
+
+        RpcClient {
+            publisher: broker.publisher(),
+            reply_queue: reply_queue.to_string(),
+            pending,
+        }
+    }
+
+    pub async fn call(&self, queue: &str, query: Query) -> Result<QueryReply, RpcError> {
+        let sent = self.send(queue, query)?;
+        wait_for_reply(&self.pending, sent, Instant::now() + RPC_TIMEOUT).await
+    }
+
+    /// Sends the query to every queue at once and waits for all the replies, in the
+    /// order of `queues`, within a single `RPC_TIMEOUT`. Used to ask every instance of
+    /// a service that splits its state between them.
+    pub async fn call_all(&self, queues: &[String], query: Query) -> Vec<Result<QueryReply, RpcError>> {
+        let deadline = Instant::now() + RPC_TIMEOUT;
+        let sent: Vec<_> = queues.iter().map(|queue| self.send(queue, query.clone())).collect();
+
+        let mut replies = Vec::with_capacity(sent.len());
+        for sent in sent {
+            replies.push(match sent {
+                Ok(sent) => wait_for_reply(&self.pending, sent, deadline).await,
+                Err(e) => Err(e),
+            });
+        }
+        replies
+    }
+
+    fn send(&self, queue: &str, query: Query) -> Result<SentQuery, RpcError> {
+        let envelope = Envelope::new(MessageType::Query, query);
+        let payload = envelope.encode().map_err(RpcError::Encode)?;
+        let properties = envelope_properties(&envelope)
+            .with_reply_to(ShortString::from(self.reply_queue.as_str()))
+            .with_expiration(ShortString::from(RPC_TIMEOUT.as_millis().to_string()));
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(envelope.message_id.clone(), reply_tx);
+        self.publisher.publish("", queue, payload, properties);
+
+        Ok(SentQuery { queue: queue.to_string(), message_id: envelope.message_id, reply_rx })
+    }
+}
+
+/// A query on its way, waiting for the reply with its message id as correlation id.
+struct SentQuery {
+    queue: String,
+    message_id: String,
+    reply_rx: oneshot::Receiver<QueryReply>,
+}
+
+async fn wait_for_reply(pending: &PendingReplies, sent: SentQuery, deadline: Instant) -> Result<QueryReply, RpcError> {
+    match tokio::time::timeout_at(deadline, sent.reply_rx).await {
+        Ok(Ok(reply)) => Ok(reply),
+        _ => {
+            pending.lock().unwrap().remove(&sent.message_id);
+            Err(RpcError::Timeout(sent.queue))
+        }
+    }
+}
+
+/// The caller waiting for the reply correlated with `correlation_id`, if it has not given up.
+fn take_waiting(pending: &PendingReplies, correlation_id: Option<&str>) -> Option<oneshot::Sender<QueryReply>> {
+    pending.lock().unwrap().remove(correlation_id?)
+}
+
+async fn task_dispatch_replies(broker: Arc<Broker>, reply_queue: String, pending: PendingReplies) {
+    let mut deliveries = broker.subscribe(&reply_queue, "rpc-client");
+
+    loop {
+        let (delivery, envelope) = deliveries.next_envelope::<QueryReply>(MessageType::QueryReply).await;
+
+        match take_waiting(&pending, envelope.correlation_id.as_deref()) {
+            Some(reply_tx) => {
+                let _ = reply_tx.send(envelope.payload);
+            }
+            None => println!("Dropping reply {} that nobody is waiting for", envelope.message_id),
+        }
+
+        if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
+            println!("Failed to ack reply on {reply_queue} ({e})");
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RpcError {
+    Encode(serde_json::Error),
+    /// No reply from the service behind the queue.
+    Timeout(String),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Encode(e) => write!(f, "failed to encode query: {e}"),
+            RpcError::Timeout(queue) => write!(f, "no reply on {queue} within {RPC_TIMEOUT:?}"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/*==================================================== SERVER ====================================================*/
+
+/// Answers the queries on `queue` with `handler`, replying to each request's
+/// `reply_to` queue with its message id as correlation id.
+pub async fn serve<F, Fut>(broker: Arc<Broker>, queue: &str, consumer_tag: &str, mut handler: F)
+where
+    F: FnMut(Query) -> Fut,
+    Fut: Future<Output = QueryReply>,
+{
+    let publisher = broker.publisher();
+    let mut deliveries = broker.subscribe(queue, consumer_tag);
+
+    loop {
+        let (delivery, request) = deliveries.next_envelope::<Query>(MessageType::Query).await;
+        let Some(reply_to) = delivery.properties.reply_to().as_ref().map(|q| q.to_string()) else {
+            deliveries.dead_letter(delivery, "query without reply_to").await;
+            continue;
+        };
+
+        let reply = Envelope::new(MessageType::QueryReply, handler(request.payload).await)
+            .correlated_with(&request.message_id);
+        if let Err(e) = publisher.publish_envelope("", &reply_to, &reply) {
+            deliveries.dead_letter(delivery, &format!("failed to encode reply: {e}")).await;
+            continue;
+        }
+
+        if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
+            println!("Failed to ack query on {queue}, it will be redelivered: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent(pending: &PendingReplies, message_id: &str) -> SentQuery {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        pending.lock().unwrap().insert(message_id.to_string(), reply_tx);
+        SentQuery { queue: "consulta_leiloes.0".to_string(), message_id: message_id.to_string(), reply_rx }
+    }
+
+    #[tokio::test]
+    async fn replies_reach_the_caller_with_the_same_correlation_id() {
+        let pending: PendingReplies = Arc::default();
+        let first = sent(&pending, "q-1");
+        let second = sent(&pending, "q-2");
+
+        assert!(take_waiting(&pending, None).is_none());
+        assert!(take_waiting(&pending, Some("unknown")).is_none());
+        take_waiting(&pending, Some("q-2")).unwrap().send(QueryReply::Auction(None)).unwrap();
+        // a second reply to the same query has nobody left to go to
+        assert!(take_waiting(&pending, Some("q-2")).is_none());
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let reply = wait_for_reply(&pending, second, deadline).await;
+        assert!(matches!(reply, Ok(QueryReply::Auction(None))));
+        assert!(pending.lock().unwrap().contains_key("q-1"));
+        drop(first);
+    }
+
+    #[tokio::test]
+    async fn callers_that_time_out_stop_waiting() {
+        let pending: PendingReplies = Arc::default();
+        let query = sent(&pending, "q-1");
+
+        let reply = wait_for_reply(&pending, query, Instant::now()).await;
+        assert!(matches!(reply, Err(RpcError::Timeout(queue)) if queue == "consulta_leiloes.0"));
+        assert!(take_waiting(&pending, Some("q-1")).is_none());
+    }
+}
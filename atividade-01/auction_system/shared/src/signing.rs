@@ -0,0 +1,501 @@
+use std::{fmt, str::FromStr};
+
+use base64::{engine::general_purpose, Engine};
+use p256::ecdsa::signature::{Signer, Verifier};
+use pkcs8::{der::zeroize::Zeroizing, EncryptedPrivateKeyInfo, PrivateKeyInfo, SecretDocument};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding},
+    Pkcs1v15Sign,
+    Pss,
+    RsaPrivateKey,
+    RsaPublicKey
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ssh_key::private::{EcdsaKeypair, KeypairData};
+use ssh_key::public::{EcdsaPublicKey, KeyData};
+
+use crate::models::{Bid, KeyRequest, KeySignature};
+
+/// How a bid is signed. It travels in the bid, so bid-srv checks each one with the
+/// scheme its client used; the client and bid-srv both sign and verify through this
+/// module, so they cannot disagree on the details.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// RSASSA-PKCS1-v1_5 over SHA-256, with the DigestInfo prefix. Assumed for bids
+    /// that do not name a scheme.
+    #[default]
+    #[serde(rename = "rsa-pkcs1v15-sha256")]
+    RsaPkcs1v15Sha256,
+    /// RSASSA-PSS over SHA-256, with a salt as long as the hash.
+    #[serde(rename = "rsa-pss-sha256")]
+    RsaPssSha256,
+    /// Ed25519 over the message itself.
+    #[serde(rename = "ed25519")]
+    Ed25519,
+    /// Deterministic ECDSA on NIST P-256 over SHA-256, the signature as the 64 bytes `r || s`.
+    #[serde(rename = "ecdsa-p256-sha256")]
+    EcdsaP256Sha256,
+}
+
+impl FromStr for SignatureScheme {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "pkcs1v15" | "rsa-pkcs1v15-sha256" => Ok(SignatureScheme::RsaPkcs1v15Sha256),
+            "pss" | "rsa-pss-sha256" => Ok(SignatureScheme::RsaPssSha256),
+            "ed25519" => Ok(SignatureScheme::Ed25519),
+            "ecdsa" | "ecdsa-p256-sha256" => Ok(SignatureScheme::EcdsaP256Sha256),
+            _ => Err(format!("unknown signature scheme '{name}', use pss, pkcs1v15, ed25519 or ecdsa")),
+        }
+    }
+}
+
+/* ========================================= KEYS ========================================= */
+
+/// Size of the RSA keys [`SigningKey::generate`] makes.
+pub const RSA_KEY_BITS: usize = 3072;
+
+/// Kinds of key a bidder can generate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    Rsa,
+    Ed25519,
+    P256,
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "rsa" => Ok(KeyAlgorithm::Rsa),
+            "ed25519" => Ok(KeyAlgorithm::Ed25519),
+            "ecdsa" | "p256" => Ok(KeyAlgorithm::P256),
+            _ => Err(format!("unknown key type '{name}', use rsa, ed25519 or ecdsa")),
+        }
+    }
+}
+
+/// The private key of a bidder.
+#[derive(Clone)]
+pub enum SigningKey {
+    Rsa(RsaPrivateKey),
+    Ed25519(ed25519_dalek::SigningKey),
+    P256(p256::ecdsa::SigningKey),
+}
+
+impl SigningKey {
+    /// Reads an unencrypted key in OpenSSH, PKCS#8, PKCS#1 (RSA) or SEC1 (P-256) PEM format.
+    pub fn from_pem(pem: &str) -> Result<SigningKey, KeyError> {
+        Self::from_pem_with_passphrase(pem, None)
+    }
+
+    /// Same as `from_pem`, and also reads OpenSSH and PKCS#8 keys encrypted with `passphrase`.
+    pub fn from_pem_with_passphrase(pem: &str, passphrase: Option<&str>) -> Result<SigningKey, KeyError> {
+        if pem.contains("BEGIN OPENSSH PRIVATE KEY") {
+            return Self::from_openssh(pem, passphrase);
+        }
+        if pem.contains("BEGIN RSA PRIVATE KEY") {
+            return RsaPrivateKey::from_pkcs1_pem(pem)
+                .map(SigningKey::Rsa)
+                .map_err(|e| KeyError::Invalid(e.to_string()));
+        }
+        if pem.contains("BEGIN EC PRIVATE KEY") {
+            return p256::SecretKey::from_sec1_pem(pem)
+                .map(|secret| SigningKey::P256(secret.into()))
+                .map_err(|e| KeyError::Invalid(e.to_string()));
+        }
+        if pem.contains("BEGIN ENCRYPTED PRIVATE KEY") {
+            let passphrase = passphrase.ok_or(KeyError::Encrypted)?;
+            let (_, document) = SecretDocument::from_pem(pem).map_err(|e| KeyError::Invalid(e.to_string()))?;
+            let decrypted = EncryptedPrivateKeyInfo::try_from(document.as_bytes())
+                .map_err(|e| KeyError::Invalid(e.to_string()))?
+                .decrypt(passphrase)
+                .map_err(|_| KeyError::WrongPassphrase)?;
+            return Self::from_pkcs8_der(decrypted.as_bytes());
+        }
+        if pem.contains("BEGIN PRIVATE KEY") {
+            let (_, document) = SecretDocument::from_pem(pem).map_err(|e| KeyError::Invalid(e.to_string()))?;
+            return Self::from_pkcs8_der(document.as_bytes());
+        }
+
+        Err(KeyError::Unsupported("key format, use an OpenSSH, PKCS#8 or PKCS#1 PEM".to_string()))
+    }
+
+    /// PKCS#8 names the algorithm inside, so only the right decoder succeeds.
+    fn from_pkcs8_der(der: &[u8]) -> Result<SigningKey, KeyError> {
+        RsaPrivateKey::from_pkcs8_der(der)
+            .map(SigningKey::Rsa)
+            .or_else(|_| ed25519_dalek::SigningKey::from_pkcs8_der(der).map(SigningKey::Ed25519))
+            .or_else(|_| p256::ecdsa::SigningKey::from_pkcs8_der(der).map(SigningKey::P256))
+            .map_err(|_| KeyError::Unsupported("PKCS#8 key that is not RSA, Ed25519 or P-256".to_string()))
+    }
+
+    fn from_openssh(pem: &str, passphrase: Option<&str>) -> Result<SigningKey, KeyError> {
+        let mut key = ssh_key::PrivateKey::from_openssh(pem).map_err(|e| KeyError::Invalid(e.to_string()))?;
+        if key.is_encrypted() {
+            let passphrase = passphrase.ok_or(KeyError::Encrypted)?;
+            key = key.decrypt(passphrase).map_err(|_| KeyError::WrongPassphrase)?;
+        }
+
+        let invalid = |e: ssh_key::Error| KeyError::Invalid(e.to_string());
+        match key.key_data() {
+            KeypairData::Rsa(keypair) => RsaPrivateKey::try_from(keypair).map(SigningKey::Rsa).map_err(invalid),
+            KeypairData::Ed25519(keypair) => ed25519_dalek::SigningKey::try_from(keypair).map(SigningKey::Ed25519).map_err(invalid),
+            KeypairData::Ecdsa(EcdsaKeypair::NistP256 { private, .. }) => p256::ecdsa::SigningKey::from_slice(private.as_slice())
+                .map(SigningKey::P256)
+                .map_err(|e| KeyError::Invalid(e.to_string())),
+            _ => Err(KeyError::Unsupported(format!("{} key", key.algorithm()))),
+        }
+    }
+
+    pub fn generate(algorithm: KeyAlgorithm) -> Result<SigningKey, KeyError> {
+        let mut rng = rand::thread_rng();
+        match algorithm {
+            KeyAlgorithm::Rsa => RsaPrivateKey::new(&mut rng, RSA_KEY_BITS)
+                .map(SigningKey::Rsa)
+                .map_err(|e| KeyError::Invalid(e.to_string())),
+            KeyAlgorithm::Ed25519 => Ok(SigningKey::Ed25519(ed25519_dalek::SigningKey::generate(&mut rng))),
+            KeyAlgorithm::P256 => Ok(SigningKey::P256(p256::ecdsa::SigningKey::random(&mut rng))),
+        }
+    }
+
+    /// PKCS#8 PEM, encrypted with scrypt and AES-256-CBC when there is a passphrase.
+    pub fn to_pem(&self, passphrase: Option<&str>) -> Result<Zeroizing<String>, KeyError> {
+        let invalid = |e: pkcs8::Error| KeyError::Invalid(e.to_string());
+        let document = match self {
+            SigningKey::Rsa(key) => key.to_pkcs8_der(),
+            SigningKey::Ed25519(key) => key.to_pkcs8_der(),
+            SigningKey::P256(key) => key.to_pkcs8_der(),
+        }.map_err(invalid)?;
+
+        let pem = match passphrase {
+            None => document.to_pem("PRIVATE KEY", LineEnding::LF),
+            Some(passphrase) => PrivateKeyInfo::try_from(document.as_bytes())
+                .and_then(|info| info.encrypt(rand::thread_rng(), passphrase))
+                .map_err(invalid)?
+                .to_pem("ENCRYPTED PRIVATE KEY", LineEnding::LF),
+        };
+        pem.map_err(|e| KeyError::Invalid(e.to_string()))
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        match self {
+            SigningKey::Rsa(key) => VerifyingKey::Rsa(key.to_public_key()),
+            SigningKey::Ed25519(key) => VerifyingKey::Ed25519(key.verifying_key()),
+            SigningKey::P256(key) => VerifyingKey::P256(*key.verifying_key()),
+        }
+    }
+
+    /// PSS for RSA keys, the only scheme for the others.
+    pub fn default_scheme(&self) -> SignatureScheme {
+        match self {
+            SigningKey::Rsa(_) => SignatureScheme::RsaPssSha256,
+            SigningKey::Ed25519(_) => SignatureScheme::Ed25519,
+            SigningKey::P256(_) => SignatureScheme::EcdsaP256Sha256,
+        }
+    }
+}
+
+/// The public key of a bidder, as bid-srv knows it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyingKey {
+    Rsa(RsaPublicKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+    P256(p256::ecdsa::VerifyingKey),
+}
+
+impl VerifyingKey {
+    /// Reads a key as an OpenSSH public key line (`ssh-ed25519 AAAA...`), a SPKI
+    /// `PUBLIC KEY` PEM or a PKCS#1 `RSA PUBLIC KEY` PEM.
+    pub fn parse(text: &str) -> Result<VerifyingKey, KeyError> {
+        let text = text.trim();
+        if text.starts_with("ssh-") || text.starts_with("ecdsa-sha2-") {
+            return Self::from_openssh(text);
+        }
+        if text.contains("BEGIN RSA PUBLIC KEY") {
+            return RsaPublicKey::from_pkcs1_pem(text)
+                .map(VerifyingKey::Rsa)
+                .map_err(|e| KeyError::Invalid(e.to_string()));
+        }
+        if text.contains("BEGIN PUBLIC KEY") {
+            return RsaPublicKey::from_public_key_pem(text)
+                .map(VerifyingKey::Rsa)
+                .or_else(|_| ed25519_dalek::VerifyingKey::from_public_key_pem(text).map(VerifyingKey::Ed25519))
+                .or_else(|_| p256::ecdsa::VerifyingKey::from_public_key_pem(text).map(VerifyingKey::P256))
+                .map_err(|_| KeyError::Unsupported("public key that is not RSA, Ed25519 or P-256".to_string()));
+        }
+
+        Err(KeyError::Unsupported("public key format, use an OpenSSH line or a PEM".to_string()))
+    }
+
+    fn from_openssh(line: &str) -> Result<VerifyingKey, KeyError> {
+        let key = ssh_key::PublicKey::from_openssh(line).map_err(|e| KeyError::Invalid(e.to_string()))?;
+
+        let invalid = |e: ssh_key::Error| KeyError::Invalid(e.to_string());
+        match key.key_data() {
+            KeyData::Rsa(public) => RsaPublicKey::try_from(public).map(VerifyingKey::Rsa).map_err(invalid),
+            KeyData::Ed25519(public) => ed25519_dalek::VerifyingKey::try_from(public).map(VerifyingKey::Ed25519).map_err(invalid),
+            KeyData::Ecdsa(public @ EcdsaPublicKey::NistP256(_)) => p256::ecdsa::VerifyingKey::try_from(public)
+                .map(VerifyingKey::P256)
+                .map_err(invalid),
+            _ => Err(KeyError::Unsupported(format!("{} key", key.algorithm()))),
+        }
+    }
+
+    /// SPKI PEM, the form bids carry their key in.
+    pub fn to_pem(&self) -> Result<String, KeyError> {
+        let pem = match self {
+            VerifyingKey::Rsa(key) => key.to_public_key_pem(LineEnding::LF),
+            VerifyingKey::Ed25519(key) => key.to_public_key_pem(LineEnding::LF),
+            VerifyingKey::P256(key) => key.to_public_key_pem(LineEnding::LF),
+        };
+        pem.map_err(|e| KeyError::Invalid(e.to_string()))
+    }
+
+    pub fn supports(&self, scheme: SignatureScheme) -> bool {
+        matches!(
+            (scheme, self),
+            (SignatureScheme::RsaPkcs1v15Sha256 | SignatureScheme::RsaPssSha256, VerifyingKey::Rsa(_))
+                | (SignatureScheme::Ed25519, VerifyingKey::Ed25519(_))
+                | (SignatureScheme::EcdsaP256Sha256, VerifyingKey::P256(_))
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    /// The format or algorithm is not one bidders can use.
+    Unsupported(String),
+    /// The key is encrypted and no passphrase was given.
+    Encrypted,
+    WrongPassphrase,
+    Invalid(String),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Unsupported(what) => write!(f, "unsupported {what}"),
+            KeyError::Encrypted => write!(f, "the key is encrypted, a passphrase is needed"),
+            KeyError::WrongPassphrase => write!(f, "wrong passphrase"),
+            KeyError::Invalid(e) => write!(f, "invalid key: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/* ========================================= SIGNATURES ========================================= */
+
+#[derive(Debug)]
+pub enum SigningError {
+    /// The signature is not valid base64.
+    Encoding(base64::DecodeError),
+    /// The scheme is for another kind of key.
+    WrongKey(SignatureScheme),
+    /// Signing failed, or the signature does not match the message and key.
+    Rsa(rsa::Error),
+    /// Same as `Rsa`, for the elliptic curve schemes.
+    Signature(p256::ecdsa::Error),
+}
+
+impl fmt::Display for SigningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SigningError::Encoding(e) => write!(f, "signature is not base64: {e}"),
+            SigningError::WrongKey(scheme) => write!(f, "{scheme:?} cannot be used with this key"),
+            SigningError::Rsa(e) => write!(f, "{e}"),
+            SigningError::Signature(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for SigningError {}
+
+impl From<rsa::Error> for SigningError {
+    fn from(e: rsa::Error) -> Self {
+        SigningError::Rsa(e)
+    }
+}
+
+impl From<p256::ecdsa::Error> for SigningError {
+    fn from(e: p256::ecdsa::Error) -> Self {
+        SigningError::Signature(e)
+    }
+}
+
+pub fn sign(scheme: SignatureScheme, key: &SigningKey, message: &[u8]) -> Result<Vec<u8>, SigningError> {
+    let signature = match (scheme, key) {
+        (SignatureScheme::RsaPkcs1v15Sha256, SigningKey::Rsa(key)) => {
+            key.sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(message))?
+        }
+        (SignatureScheme::RsaPssSha256, SigningKey::Rsa(key)) => {
+            key.sign_with_rng(&mut rand::thread_rng(), Pss::new::<Sha256>(), &Sha256::digest(message))?
+        }
+        (SignatureScheme::Ed25519, SigningKey::Ed25519(key)) => {
+            let signature: ed25519_dalek::Signature = key.try_sign(message)?;
+            signature.to_vec()
+        }
+        (SignatureScheme::EcdsaP256Sha256, SigningKey::P256(key)) => {
+            let signature: p256::ecdsa::Signature = key.try_sign(message)?;
+            signature.to_vec()
+        }
+        _ => return Err(SigningError::WrongKey(scheme)),
+    };
+    Ok(signature)
+}
+
+pub fn verify(
+    scheme: SignatureScheme,
+    key: &VerifyingKey,
+    message: &[u8],
+    signature: &[u8]
+) -> Result<(), SigningError> {
+    match (scheme, key) {
+        (SignatureScheme::RsaPkcs1v15Sha256, VerifyingKey::Rsa(key)) => {
+            key.verify(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(message), signature)?
+        }
+        (SignatureScheme::RsaPssSha256, VerifyingKey::Rsa(key)) => {
+            key.verify(Pss::new::<Sha256>(), &Sha256::digest(message), signature)?
+        }
+        (SignatureScheme::Ed25519, VerifyingKey::Ed25519(key)) => {
+            key.verify(message, &ed25519_dalek::Signature::from_slice(signature)?)?
+        }
+        (SignatureScheme::EcdsaP256Sha256, VerifyingKey::P256(key)) => {
+            key.verify(message, &p256::ecdsa::Signature::from_slice(signature)?)?
+        }
+        _ => return Err(SigningError::WrongKey(scheme)),
+    }
+    Ok(())
+}
+
+/// Signs `Bid::signed_payload` and stores the base64 signature and the scheme in the bid.
+pub fn sign_bid(bid: &mut Bid, scheme: SignatureScheme, key: &SigningKey) -> Result<(), SigningError> {
+    let signature = sign(scheme, key, &bid.signed_payload())?;
+    bid.signature = general_purpose::STANDARD.encode(signature);
+    bid.signature_scheme = scheme;
+    Ok(())
+}
+
+pub fn verify_bid(bid: &Bid, key: &VerifyingKey) -> Result<(), SigningError> {
+    let signature = general_purpose::STANDARD
+        .decode(&bid.signature)
+        .map_err(SigningError::Encoding)?;
+    verify(bid.signature_scheme, key, &bid.signed_payload(), &signature)
+}
+
+/// Signs `KeyRequest::signed_payload`, for the `signature` or the `proof` of the request.
+pub fn sign_key_request(
+    request: &KeyRequest,
+    scheme: SignatureScheme,
+    key: &SigningKey
+) -> Result<KeySignature, SigningError> {
+    let signature = sign(scheme, key, &request.signed_payload())?;
+    Ok(KeySignature { scheme, signature: general_purpose::STANDARD.encode(signature) })
+}
+
+pub fn verify_key_request(
+    request: &KeyRequest,
+    signature: &KeySignature,
+    key: &VerifyingKey
+) -> Result<(), SigningError> {
+    let bytes = general_purpose::STANDARD
+        .decode(&signature.signature)
+        .map_err(SigningError::Encoding)?;
+    verify(signature.scheme, key, &request.signed_payload(), &bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pkcs8::EncodePrivateKey;
+
+    fn bid() -> Bid {
+        Bid {
+            auction_id: 1,
+            client_id: 2,
+            value: "10".parse().unwrap(),
+            signature: String::new(),
+            public_key: String::new(),
+            valid: false,
+            quantity: 1,
+            nonce: 3,
+            timestamp: 4,
+            signature_scheme: SignatureScheme::default(),
+        }
+    }
+
+    #[test]
+    fn every_scheme_round_trips_and_rejects_tampering() {
+        let rsa_key = SigningKey::Rsa(RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap());
+        let ed25519_key = SigningKey::Ed25519(ed25519_dalek::SigningKey::generate(&mut rand::thread_rng()));
+        let p256_key = SigningKey::P256(p256::ecdsa::SigningKey::random(&mut rand::thread_rng()));
+        let mut bid = bid();
+
+        for (scheme, key) in [
+            (SignatureScheme::RsaPkcs1v15Sha256, &rsa_key),
+            (SignatureScheme::RsaPssSha256, &rsa_key),
+            (SignatureScheme::Ed25519, &ed25519_key),
+            (SignatureScheme::EcdsaP256Sha256, &p256_key),
+        ] {
+            sign_bid(&mut bid, scheme, key).unwrap();
+            assert!(verify_bid(&bid, &key.verifying_key()).is_ok());
+
+            let tampered = Bid { value: "11".parse().unwrap(), ..bid.clone() };
+            assert!(verify_bid(&tampered, &key.verifying_key()).is_err());
+        }
+
+        // a signature only counts with the scheme and kind of key it was made with
+        assert!(matches!(sign(SignatureScheme::Ed25519, &rsa_key, b"bid"), Err(SigningError::WrongKey(_))));
+        sign_bid(&mut bid, SignatureScheme::RsaPssSha256, &rsa_key).unwrap();
+        let other_scheme = Bid { signature_scheme: SignatureScheme::RsaPkcs1v15Sha256, ..bid.clone() };
+        assert!(verify_bid(&other_scheme, &rsa_key.verifying_key()).is_err());
+
+        // nor does the old signature over the bare hash
+        let SigningKey::Rsa(private_key) = &rsa_key else { unreachable!() };
+        let hashed = Sha256::digest(bid.signed_payload());
+        let unprefixed = private_key.sign(Pkcs1v15Sign::new_unprefixed(), &hashed).unwrap();
+        let legacy = Bid { signature: general_purpose::STANDARD.encode(unprefixed), ..other_scheme };
+        assert!(verify_bid(&legacy, &rsa_key.verifying_key()).is_err());
+    }
+
+    #[test]
+    fn loads_pkcs8_and_openssh_keys() {
+        let ed25519_key = ed25519_dalek::SigningKey::generate(&mut rand::thread_rng());
+        let pkcs8 = ed25519_key.to_pkcs8_pem(LineEnding::LF).unwrap();
+        let loaded = SigningKey::from_pem(&pkcs8).unwrap();
+        assert_eq!(loaded.verifying_key(), VerifyingKey::Ed25519(ed25519_key.verifying_key()));
+        assert_eq!(loaded.default_scheme(), SignatureScheme::Ed25519);
+
+        let public_pem = loaded.verifying_key().to_pem().unwrap();
+        assert_eq!(VerifyingKey::parse(&public_pem).unwrap(), loaded.verifying_key());
+
+        let openssh = ssh_key::PrivateKey::random(&mut rand::thread_rng(), ssh_key::Algorithm::new("ecdsa-sha2-nistp256").unwrap()).unwrap();
+        let loaded = SigningKey::from_pem(&openssh.to_openssh(ssh_key::LineEnding::LF).unwrap()).unwrap();
+        let public_line = openssh.public_key().to_openssh().unwrap();
+        assert_eq!(VerifyingKey::parse(&public_line).unwrap(), loaded.verifying_key());
+        assert!(loaded.verifying_key().supports(SignatureScheme::EcdsaP256Sha256));
+        assert!(!loaded.verifying_key().supports(SignatureScheme::RsaPssSha256));
+    }
+
+    #[test]
+    fn generated_keys_round_trip_with_a_passphrase() {
+        let key = SigningKey::generate(KeyAlgorithm::P256).unwrap();
+
+        let plain = key.to_pem(None).unwrap();
+        assert_eq!(SigningKey::from_pem(&plain).unwrap().verifying_key(), key.verifying_key());
+
+        let encrypted = key.to_pem(Some("correct horse")).unwrap();
+        assert!(matches!(SigningKey::from_pem(&encrypted), Err(KeyError::Encrypted)));
+        assert!(matches!(
+            SigningKey::from_pem_with_passphrase(&encrypted, Some("wrong horse")),
+            Err(KeyError::WrongPassphrase)
+        ));
+        let decrypted = SigningKey::from_pem_with_passphrase(&encrypted, Some("correct horse")).unwrap();
+        assert_eq!(decrypted.verifying_key(), key.verifying_key());
+    }
+}
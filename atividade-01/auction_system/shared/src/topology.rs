@@ -0,0 +1,455 @@
+use std::fmt;
+
+use lapin::{
+    options::{ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions},
+    protocol::{AMQPErrorKind, AMQPSoftError},
+    types::{AMQPValue, FieldTable, LongString, ShortString},
+    Channel, Connection, ErrorKind, ExchangeKind,
+};
+
+use crate::rpc::RPC_TIMEOUT;
+
+/*========================================= NAMES =========================================*/
+
+/// Fanout exchange, auction-srv announces every auction that starts.
+pub const LEILAO_INICIADO: &str = "leilao_iniciado";
+/// Fanout exchange, auction-srv announces auctions cancelled by the operator.
+pub const LEILAO_CANCELADO: &str = "leilao_cancelado";
+/// Fanout exchange, auction-srv announces the new end of an extended auction.
+pub const LEILAO_PRORROGADO: &str = "leilao_prorrogado";
+/// Queue, auction-srv tells bid-srv which auction just ended.
+pub const LEILAO_FINALIZADO: &str = "leilao_finalizado";
+/// Queue per auction-srv instance, bid-srv asks the owner of an auction to push
+/// back its end after a bid in its soft-close window. See [`auction_srv_queue`].
+pub const PRORROGACAO_SOLICITADA: &str = "prorrogacao_solicitada";
+/// Queue per auction-srv instance, bid-srv asks the owner of a Dutch auction to end
+/// it once it took a bid. See [`auction_srv_queue`].
+pub const ENCERRAMENTO_SOLICITADO: &str = "encerramento_solicitado";
+/// Queue, clients send their signed bids to bid-srv.
+pub const LANCE_REALIZADO: &str = "lance_realizado";
+/// Queue, bid-srv forwards accepted bids to notification-srv.
+pub const LANCE_VALIDADO: &str = "lance_validado";
+/// Queue, bid-srv sends the winner of each auction to notification-srv.
+pub const LEILAO_VENCEDOR: &str = "leilao_vencedor";
+/// Topic exchange, notification-srv publishes to `leilao_<id>` routing keys.
+pub const NOTIFICACOES: &str = "notificacoes";
+
+/// Direct exchange, bid-srv tells a client why its bid was refused, routed by `cliente_<id>`.
+pub const LANCE_REJEITADO: &str = "lance_rejeitado";
+
+/// Queue per auction-srv instance, each one answers queries about the auctions it owns.
+/// See [`auction_srv_queue`].
+pub const CONSULTA_LEILOES: &str = "consulta_leiloes";
+/// Queue, bid-srv answers queries about the bids of an auction.
+pub const CONSULTA_LANCES: &str = "consulta_lances";
+/// Queue, bid-srv registers, rotates and revokes the public keys of clients.
+pub const REGISTRO_CHAVES: &str = "registro_chaves";
+
+/// Fanout exchange for messages the services gave up on, and the queue bound to it.
+/// Every service queue dead-letters here; the `dead-letter` tool reads the queue.
+pub const MENSAGENS_MORTAS: &str = "mensagens_mortas";
+
+/// bid-srv's own subscription to `leilao_iniciado`. It is named, unlike the
+/// other subscribers, so auctions that start while bid-srv is down are kept.
+pub const BID_SRV_LEILAO_INICIADO: &str = "bid-srv.leilao_iniciado";
+pub const BID_SRV_LEILAO_CANCELADO: &str = "bid-srv.leilao_cancelado";
+pub const BID_SRV_LEILAO_PRORROGADO: &str = "bid-srv.leilao_prorrogado";
+/// notification-srv's subscriptions, which turn these events into notifications.
+pub const NOTIFICATION_SRV_LEILAO_CANCELADO: &str = "notification-srv.leilao_cancelado";
+pub const NOTIFICATION_SRV_LEILAO_PRORROGADO: &str = "notification-srv.leilao_prorrogado";
+
+/// auction-srv's subscription to every auction on `notificacoes`, for the high bids
+/// shown in its console. Exclusive, declared by auction-srv itself.
+pub const AUCTION_SRV_NOTIFICACOES: &str = "auction-srv.notificacoes";
+
+/// Client's own subscription to `leilao_iniciado`.
+pub fn client_started_queue(client_id: u32) -> String {
+    format!("cliente_{client_id}.leilao_iniciado")
+}
+
+/// Client's queue on `notificacoes`, bound to the routing key of every auction it follows.
+pub fn client_notification_queue(client_id: u32) -> String {
+    format!("cliente_{client_id}.notificacoes")
+}
+
+/// Client's queue on `lance_rejeitado`, bound to its own routing key.
+pub fn client_rejection_queue(client_id: u32) -> String {
+    format!("cliente_{client_id}.lance_rejeitado")
+}
+
+/// Client's queue for the replies to its queries.
+pub fn client_reply_queue(client_id: u32) -> String {
+    format!("cliente_{client_id}.respostas")
+}
+
+/// Routing key used on `lance_rejeitado` for messages addressed to one client.
+pub fn client_routing_key(client_id: u32) -> String {
+    format!("cliente_{client_id}")
+}
+
+/// Routing key used on `notificacoes` for everything about one auction.
+pub fn auction_routing_key(auction_id: u32) -> String {
+    format!("leilao_{auction_id}")
+}
+
+/// The auction-srv instance that allocated `auction_id`, and so schedules the auction.
+pub fn auction_srv_instance(auction_id: u32, auction_srv_instances: u32) -> u32 {
+    auction_id.saturating_sub(1) % auction_srv_instances
+}
+
+/// Queue `name` of one auction-srv instance. Requests about an auction only make
+/// sense to the instance that owns it, so they are not shared between instances.
+pub fn auction_srv_queue(name: &str, instance: u32) -> String {
+    format!("{name}.{instance}")
+}
+
+/*========================================= SPECS =========================================*/
+
+pub struct ExchangeSpec {
+    pub name: &'static str,
+    pub kind: ExchangeKind,
+    pub options: ExchangeDeclareOptions,
+    pub arguments: FieldTable,
+}
+
+pub struct QueueSpec {
+    pub name: String,
+    pub options: QueueDeclareOptions,
+    pub arguments: FieldTable,
+}
+
+pub struct BindingSpec {
+    pub queue: &'static str,
+    pub exchange: &'static str,
+    pub routing_key: &'static str,
+}
+
+pub fn exchanges() -> Vec<ExchangeSpec> {
+    vec![
+        exchange(LEILAO_INICIADO, ExchangeKind::Fanout),
+        exchange(LEILAO_CANCELADO, ExchangeKind::Fanout),
+        exchange(LEILAO_PRORROGADO, ExchangeKind::Fanout),
+        exchange(NOTIFICACOES, ExchangeKind::Topic),
+        exchange(LANCE_REJEITADO, ExchangeKind::Direct),
+        exchange(MENSAGENS_MORTAS, ExchangeKind::Fanout),
+    ]
+}
+
+pub fn queues(auction_srv_instances: u32) -> Vec<QueueSpec> {
+    let mut queues = vec![
+        service_queue(LEILAO_FINALIZADO),
+        service_queue(LANCE_REALIZADO),
+        service_queue(LANCE_VALIDADO),
+        service_queue(LEILAO_VENCEDOR),
+        service_queue(BID_SRV_LEILAO_INICIADO),
+        service_queue(BID_SRV_LEILAO_CANCELADO),
+        service_queue(BID_SRV_LEILAO_PRORROGADO),
+        service_queue(NOTIFICATION_SRV_LEILAO_CANCELADO),
+        service_queue(NOTIFICATION_SRV_LEILAO_PRORROGADO),
+        request_queue(CONSULTA_LANCES),
+        request_queue(REGISTRO_CHAVES),
+        queue(MENSAGENS_MORTAS),
+    ];
+
+    for instance in 0..auction_srv_instances {
+        queues.push(service_queue(&auction_srv_queue(PRORROGACAO_SOLICITADA, instance)));
+        queues.push(service_queue(&auction_srv_queue(ENCERRAMENTO_SOLICITADO, instance)));
+        queues.push(request_queue(&auction_srv_queue(CONSULTA_LEILOES, instance)));
+    }
+
+    queues
+}
+
+pub fn bindings() -> Vec<BindingSpec> {
+    vec![
+        BindingSpec { queue: BID_SRV_LEILAO_INICIADO, exchange: LEILAO_INICIADO, routing_key: "" },
+        BindingSpec { queue: BID_SRV_LEILAO_CANCELADO, exchange: LEILAO_CANCELADO, routing_key: "" },
+        BindingSpec { queue: BID_SRV_LEILAO_PRORROGADO, exchange: LEILAO_PRORROGADO, routing_key: "" },
+        BindingSpec { queue: NOTIFICATION_SRV_LEILAO_CANCELADO, exchange: LEILAO_CANCELADO, routing_key: "" },
+        BindingSpec { queue: NOTIFICATION_SRV_LEILAO_PRORROGADO, exchange: LEILAO_PRORROGADO, routing_key: "" },
+        BindingSpec { queue: MENSAGENS_MORTAS, exchange: MENSAGENS_MORTAS, routing_key: "" },
+    ]
+}
+
+/// Durable, so the exchange survives a broker restart.
+fn exchange(name: &'static str, kind: ExchangeKind) -> ExchangeSpec {
+    ExchangeSpec {
+        name,
+        kind,
+        options: ExchangeDeclareOptions { durable: true, ..ExchangeDeclareOptions::default() },
+        arguments: FieldTable::default(),
+    }
+}
+
+/// Durable, so the queue and its persistent messages survive a broker restart.
+fn queue(name: &str) -> QueueSpec {
+    QueueSpec {
+        name: name.to_string(),
+        options: QueueDeclareOptions { durable: true, ..QueueDeclareOptions::default() },
+        arguments: FieldTable::default(),
+    }
+}
+
+/// Queue consumed by a service. Messages rejected without requeueing go to `mensagens_mortas`.
+fn service_queue(name: &str) -> QueueSpec {
+    let mut arguments = FieldTable::default();
+    arguments.insert(
+        ShortString::from("x-dead-letter-exchange"),
+        AMQPValue::LongString(LongString::from(MENSAGENS_MORTAS)),
+    );
+
+    QueueSpec { arguments, ..queue(name) }
+}
+
+/// Queue of RPC requests. A request nobody answered in time has no one waiting for
+/// the reply, so it expires instead of being dead-lettered.
+fn request_queue(name: &str) -> QueueSpec {
+    let mut arguments = FieldTable::default();
+    arguments.insert(
+        ShortString::from("x-message-ttl"),
+        AMQPValue::LongUInt(RPC_TIMEOUT.as_millis() as u32),
+    );
+
+    QueueSpec { arguments, ..queue(name) }
+}
+
+/*========================================= DECLARE =========================================*/
+
+/// Declares every shared exchange, queue and binding. Safe to call from every service.
+pub async fn declare_topology(channel: &Channel, auction_srv_instances: u32) -> lapin::Result<()> {
+    for spec in exchanges() {
+        if let Err(e) = channel.exchange_declare(spec.name, spec.kind, spec.options, spec.arguments).await {
+            if soft_error(&e) == Some(AMQPSoftError::PRECONDITIONFAILED) {
+                println!(
+                    "Exchange {0} already exists with other options, probably declared by an older version. \
+                     Delete it (rabbitmqadmin delete exchange name={0}) and start the services again.",
+                    spec.name
+                );
+            }
+            return Err(e);
+        }
+    }
+
+    for spec in queues(auction_srv_instances) {
+        if let Err(e) = channel.queue_declare(&spec.name, spec.options, spec.arguments).await {
+            // the broker cannot change the arguments or durability of a queue in place
+            if soft_error(&e) == Some(AMQPSoftError::PRECONDITIONFAILED) {
+                println!(
+                    "Queue {0} already exists with other options, probably declared by an older version. \
+                     Drain it, delete it (rabbitmqctl delete_queue {0}) and start the services again.",
+                    spec.name
+                );
+            }
+            return Err(e);
+        }
+    }
+
+    for spec in bindings() {
+        channel.queue_bind(
+            spec.queue,
+            spec.exchange,
+            spec.routing_key,
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        ).await?;
+    }
+
+    Ok(())
+}
+
+/// Declares an exclusive queue, which lives as long as the connection that declared it.
+/// An empty `name` lets the broker pick one; the actual name is returned.
+pub async fn declare_exclusive_queue(channel: &Channel, name: &str) -> lapin::Result<String> {
+    let queue = channel.queue_declare(
+        name,
+        QueueDeclareOptions { exclusive: true, ..QueueDeclareOptions::default() },
+        FieldTable::default(),
+    ).await?;
+
+    Ok(queue.name().to_string())
+}
+
+/// Declares an exclusive queue bound to `exchange` and returns its name.
+/// Used by the processes that only need events while they are running.
+pub async fn declare_subscriber_queue(
+    channel: &Channel,
+    name: &str,
+    exchange: &str,
+    routing_key: &str,
+) -> lapin::Result<String> {
+    let queue = declare_exclusive_queue(channel, name).await?;
+
+    channel.queue_bind(
+        &queue,
+        exchange,
+        routing_key,
+        QueueBindOptions::default(),
+        FieldTable::default(),
+    ).await?;
+
+    Ok(queue)
+}
+
+/*========================================= VERIFY =========================================*/
+
+#[derive(Debug)]
+pub enum TopologyError {
+    /// The broker already has the entity, declared with different options.
+    Mismatch { entity: String, details: String },
+    Broker(lapin::Error),
+}
+
+impl fmt::Display for TopologyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopologyError::Mismatch { entity, details } => write!(
+                f,
+                "{entity} already exists on the broker with different options ({details}); delete it or align the topology module"
+            ),
+            TopologyError::Broker(e) => write!(f, "broker error while verifying topology: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TopologyError {}
+
+/// Checks that the exchanges and queues already on the broker were declared with
+/// the options in this module. Entities that do not exist yet are not created.
+///
+/// A mismatched declaration closes the channel it was made on, so each check runs
+/// on its own short-lived channel.
+pub async fn verify_topology(conn: &Connection, auction_srv_instances: u32) -> Result<(), TopologyError> {
+    for spec in exchanges() {
+        let entity = format!("exchange '{}'", spec.name);
+        let passive = ExchangeDeclareOptions { passive: true, ..spec.options };
+        let kind = spec.kind.clone();
+        if !exists(conn, |ch| async move {
+            ch.exchange_declare(spec.name, kind, passive, FieldTable::default()).await
+        }).await? {
+            continue;
+        }
+
+        check(conn, &entity, |ch| async move {
+            ch.exchange_declare(spec.name, spec.kind, spec.options, spec.arguments).await
+        }).await?;
+    }
+
+    for spec in queues(auction_srv_instances) {
+        let entity = format!("queue '{}'", spec.name);
+        let passive = QueueDeclareOptions { passive: true, ..spec.options };
+        let name = &spec.name;
+        if !exists(conn, |ch| async move {
+            ch.queue_declare(name, passive, FieldTable::default()).await.map(|_| ())
+        }).await? {
+            continue;
+        }
+
+        check(conn, &entity, |ch| async move {
+            ch.queue_declare(&spec.name, spec.options, spec.arguments).await.map(|_| ())
+        }).await?;
+    }
+
+    Ok(())
+}
+
+async fn exists<F, Fut>(conn: &Connection, declare: F) -> Result<bool, TopologyError>
+where
+    F: FnOnce(Channel) -> Fut,
+    Fut: Future<Output = lapin::Result<()>>,
+{
+    let channel = conn.create_channel().await.map_err(TopologyError::Broker)?;
+    match declare(channel.clone()).await {
+        Ok(()) => {
+            let _ = channel.close(200, "topology check").await;
+            Ok(true)
+        }
+        Err(e) if soft_error(&e) == Some(AMQPSoftError::NOTFOUND) => Ok(false),
+        Err(e) => Err(TopologyError::Broker(e)),
+    }
+}
+
+async fn check<F, Fut>(conn: &Connection, entity: &str, declare: F) -> Result<(), TopologyError>
+where
+    F: FnOnce(Channel) -> Fut,
+    Fut: Future<Output = lapin::Result<()>>,
+{
+    let channel = conn.create_channel().await.map_err(TopologyError::Broker)?;
+    match declare(channel.clone()).await {
+        Ok(()) => {
+            let _ = channel.close(200, "topology check").await;
+            Ok(())
+        }
+        Err(e) if soft_error(&e) == Some(AMQPSoftError::PRECONDITIONFAILED) => Err(TopologyError::Mismatch {
+            entity: entity.to_string(),
+            details: e.to_string(),
+        }),
+        Err(e) => Err(TopologyError::Broker(e)),
+    }
+}
+
+fn soft_error(error: &lapin::Error) -> Option<AMQPSoftError> {
+    match error.kind() {
+        ErrorKind::ProtocolError(amqp_error) => match amqp_error.kind() {
+            AMQPErrorKind::Soft(soft) => Some(soft.clone()),
+            AMQPErrorKind::Hard(_) => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argument<'a>(spec: &'a QueueSpec, name: &str) -> Option<&'a AMQPValue> {
+        spec.arguments.inner().get(name)
+    }
+
+    #[test]
+    fn failed_messages_end_up_in_mensagens_mortas() {
+        for spec in queues(2) {
+            let dead_letter_exchange = argument(&spec, "x-dead-letter-exchange").and_then(AMQPValue::as_long_string);
+            if spec.name == MENSAGENS_MORTAS {
+                // rejecting a dead letter must not send it around again
+                assert!(dead_letter_exchange.is_none());
+            } else if argument(&spec, "x-message-ttl").is_none() {
+                assert_eq!(dead_letter_exchange.map(|e| e.to_string()).as_deref(), Some(MENSAGENS_MORTAS), "{}", spec.name);
+            }
+        }
+
+        assert!(bindings().iter().any(|b| b.queue == MENSAGENS_MORTAS && b.exchange == MENSAGENS_MORTAS));
+    }
+
+    #[test]
+    fn every_auction_srv_instance_gets_its_own_request_queues() {
+        let specs = queues(2);
+        let find = |name: String| specs.iter().find(|spec| spec.name == name).unwrap_or_else(|| panic!("{name} missing"));
+
+        for instance in 0..2 {
+            let extensions = find(auction_srv_queue(PRORROGACAO_SOLICITADA, instance));
+            assert!(argument(extensions, "x-dead-letter-exchange").is_some());
+            let close_requests = find(auction_srv_queue(ENCERRAMENTO_SOLICITADO, instance));
+            assert!(argument(close_requests, "x-dead-letter-exchange").is_some());
+            let queries = find(auction_srv_queue(CONSULTA_LEILOES, instance));
+            assert_eq!(
+                argument(queries, "x-message-ttl"),
+                Some(&AMQPValue::LongUInt(RPC_TIMEOUT.as_millis() as u32))
+            );
+        }
+        assert!(specs.iter().all(|spec| spec.name != auction_srv_queue(CONSULTA_LEILOES, 2)));
+        assert_eq!(queues(1).len() + 3, specs.len());
+    }
+
+    #[test]
+    fn shared_topology_is_durable_and_bindings_only_name_declared_entities() {
+        let exchanges = exchanges();
+        let queues = queues(1);
+        assert!(exchanges.iter().all(|spec| spec.options.durable));
+        assert!(queues.iter().all(|spec| spec.options.durable && !spec.options.exclusive));
+
+        for binding in bindings() {
+            assert!(queues.iter().any(|spec| spec.name == binding.queue), "{}", binding.queue);
+            assert!(exchanges.iter().any(|spec| spec.name == binding.exchange), "{}", binding.exchange);
+        }
+    }
+}